@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE traces
+    ADD COLUMN captures TEXT[] NOT NULL DEFAULT '{}'
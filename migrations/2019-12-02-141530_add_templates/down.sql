@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE traces
+    DROP COLUMN template_types,
+    DROP COLUMN templates
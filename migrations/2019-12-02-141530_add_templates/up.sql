@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+ALTER TABLE traces
+    ADD COLUMN template_types TEXT[] NOT NULL DEFAULT '{}',
+    ADD COLUMN templates      TEXT[] NOT NULL DEFAULT '{}'
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE traces
+    ADD COLUMN kernel_probes TEXT[] NOT NULL DEFAULT '{}'
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+
+CREATE TABLE trace_runs
+(
+    id          SERIAL PRIMARY KEY,
+    trace_id    INTEGER     NOT NULL,
+    trace_type  VARCHAR     NOT NULL,
+    script      VARCHAR     NOT NULL,
+    started_by  VARCHAR     NOT NULL,
+    start_time  TIMESTAMPTZ NOT NULL,
+    end_time    TIMESTAMPTZ,
+    exit_status VARCHAR,
+    stderr      TEXT,
+    chunks      INTEGER     NOT NULL DEFAULT 0,
+    bytes       BIGINT      NOT NULL DEFAULT 0
+)
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+
+CREATE TABLE api_keys
+(
+    id         SERIAL PRIMARY KEY,
+    name       VARCHAR     NOT NULL UNIQUE,
+    secret     VARCHAR     NOT NULL,
+    scope      VARCHAR     NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+)
@@ -1,13 +1,17 @@
 use std::fs::File;
-use std::io::{BufWriter, stdout, Write};
+use std::io::{BufWriter, Read, stdout, Write};
 
 use clap::*;
 use regex::Regex;
 
+use crate::db::model::key::Scope;
 use crate::http_server::{PutTrace, StartTrace};
+use crate::tracer::Launch;
 
 fn get_matches<'a>() -> ArgMatches<'a> {
-    let values = vec!["STAP", "BPF"];
+    let values = crate::tracer::names();
+    let modes = vec!["stack", "latency"];
+    let scopes = vec!["read", "run", "admin"];
     App::new("lambda-endpoint")
         .subcommand(SubCommand::with_name("endpoint").about("start endpoint")
             .arg(Arg::with_name("config").short("c").long("config").value_name("CONFIG")
@@ -18,11 +22,18 @@ fn get_matches<'a>() -> ArgMatches<'a> {
             .arg(Arg::with_name("process").short("p").long("proc").value_name("PROC")
                 .help("path to the process to trace").required(true))
             .arg(Arg::with_name("function").short("f").long("func").value_name("FUNC")
-                .help("functions to trace, allow multiple").multiple(true).required(true))
+                .help("functions to trace, allow multiple").multiple(true).required_unless("kernel"))
+            .arg(Arg::with_name("kernel").short("k").long("kernel").value_name("PROBE")
+                .help("kernel probes to record, in the form of 'tracepoint:CATEGORY:NAME', 'kprobe:FUNC' or 'syscall:NAME', allow multiple")
+                .multiple(true))
             .arg(Arg::with_name("environment").short("e").long("env").value_name("ENV")
                 .help("trace environment variable, in the form of '(ENV_NAME, env_value)'").multiple(true))
             .arg(Arg::with_name("option").short("o").long("opt").value_name("OPT")
-                .help("options to be append when tracing").multiple(true)))
+                .help("options to be append when tracing").multiple(true))
+            .arg(Arg::with_name("capture").short("a").long("capture").value_name("CAPTURE")
+                .help("values to capture, in the form of 'func=arg0,arg1,retval', allow multiple").multiple(true))
+            .arg(Arg::with_name("template").long("template").value_name("TEMPLATE")
+                .help("custom probe body, in the form of 'TYPE=path/to/body', allow multiple").multiple(true)))
         .subcommand(SubCommand::with_name("delete").about("delete trace")
             .arg(Arg::with_name("config").short("c").long("config").value_name("CONFIG")
                 .help("path to the configuration").required(true))
@@ -45,8 +56,66 @@ fn get_matches<'a>() -> ArgMatches<'a> {
                 .help("duration of tracing").required(true))
             .arg(Arg::with_name("type").short("t").long("type").possible_values(values.as_slice())
                 .value_name("TYPE").help("the type of trace").required(true))
+            .arg(Arg::with_name("mode").short("m").long("mode").possible_values(modes.as_slice())
+                .value_name("MODE").help("what to report, defaults to stack"))
+            .arg(Arg::with_name("pid").long("pid").value_name("PID")
+                .help("only trace the process with the given pid"))
+            .arg(Arg::with_name("cgroup").long("cgroup").value_name("CGROUP")
+                .help("only trace the processes in the given cgroup, relative to /sys/fs/cgroup"))
+            .arg(Arg::with_name("launch").short("l").long("launch")
+                .help("start the process under the tracer and trace it until it exits"))
+            .arg(Arg::with_name("argument").long("arg").value_name("ARG").requires("launch")
+                .help("arguments of the launched process, allow multiple").multiple(true))
+            .arg(Arg::with_name("working_dir").long("cwd").value_name("DIR").requires("launch")
+                .help("working directory of the launched process"))
             .arg(Arg::with_name("output").short("o").long("out").value_name("OUTPUT")
                 .help("output file, will choose stdout if not set")))
+        .subcommand(SubCommand::with_name("runs").about("list the history of runs")
+            .arg(Arg::with_name("config").short("c").long("config").value_name("CONFIG")
+                .help("path to the configuration").required(true))
+            .arg(Arg::with_name("id").short("i").long("i").value_name("ID")
+                .help("only list the runs of the given trace"))
+            .arg(Arg::with_name("limit").short("n").long("limit").value_name("LIMIT")
+                .help("number of runs to list, defaults to 100")))
+        .subcommand(SubCommand::with_name("preview").about("print the script of the given trace without running it")
+            .arg(Arg::with_name("config").short("c").long("config").value_name("CONFIG")
+                .help("path to the configuration").required(true))
+            .arg(Arg::with_name("id").short("i").long("i").value_name("ID")
+                .help("id of the trace to be got").required(true))
+            .arg(Arg::with_name("duration").short("d").long("duration").value_name("DURATION")
+                .help("duration of tracing").required(true))
+            .arg(Arg::with_name("type").short("t").long("type").possible_values(values.as_slice())
+                .value_name("TYPE").help("the type of trace").required(true))
+            .arg(Arg::with_name("mode").short("m").long("mode").possible_values(modes.as_slice())
+                .value_name("MODE").help("what to report, defaults to stack"))
+            .arg(Arg::with_name("pid").long("pid").value_name("PID")
+                .help("only trace the process with the given pid"))
+            .arg(Arg::with_name("cgroup").long("cgroup").value_name("CGROUP")
+                .help("only trace the processes in the given cgroup, relative to /sys/fs/cgroup"))
+            .arg(Arg::with_name("check").long("check")
+                .help("also compile the script without attaching any probe")))
+        .subcommand(SubCommand::with_name("create-key").about("create an api key, its secret is printed once")
+            .arg(Arg::with_name("config").short("c").long("config").value_name("CONFIG")
+                .help("path to the configuration").required(true))
+            .arg(Arg::with_name("name").short("n").long("name").value_name("NAME")
+                .help("name of the key, sent in the key field of the authorization header").required(true))
+            .arg(Arg::with_name("scope").short("s").long("scope").possible_values(scopes.as_slice())
+                .value_name("SCOPE").help("what the key may do, each scope includes the previous ones").required(true)))
+        .subcommand(SubCommand::with_name("list-keys").about("list api keys")
+            .arg(Arg::with_name("config").short("c").long("config").value_name("CONFIG")
+                .help("path to the configuration").required(true)))
+        .subcommand(SubCommand::with_name("revoke-key").about("revoke an api key")
+            .arg(Arg::with_name("config").short("c").long("config").value_name("CONFIG")
+                .help("path to the configuration").required(true))
+            .arg(Arg::with_name("name").short("n").long("name").value_name("NAME")
+                .help("name of the key to be revoked").required(true)))
+        .subcommand(SubCommand::with_name("helper").about("run the privileged helper, must be started as root")
+            .arg(Arg::with_name("config").short("c").long("config").value_name("CONFIG")
+                .help("path to the configuration").required(true))
+            .arg(Arg::with_name("uid").short("u").long("uid").value_name("UID")
+                .help("uid of the endpoint allowed to use the helper").required(true))
+            .arg(Arg::with_name("socket").short("s").long("socket").value_name("SOCKET")
+                .help("path of the socket, defaults to helper_socket of the configuration")))
         .get_matches()
 }
 
@@ -94,6 +163,24 @@ pub fn get_task() -> StartTrace {
             trace_type: SUB_COMMAND.1.value_of("type").unwrap().to_string(),
             trace_id: get_id(),
             lasting: t,
+            mode: SUB_COMMAND.1.value_of("mode").and_then(|x| x.parse().ok()).unwrap_or_default(),
+            pid: SUB_COMMAND.1.value_of("pid").map(|x| match x.parse() {
+                Ok(pid) => pid,
+                Err(_) => {
+                    eprintln!("invalid pid");
+                    std::process::exit(1)
+                }
+            }),
+            cgroup: SUB_COMMAND.1.value_of("cgroup").map(|x| x.to_string()),
+            launch: if SUB_COMMAND.1.is_present("launch") {
+                Some(Launch {
+                    args: get_multiple("argument"),
+                    working_dir: SUB_COMMAND.1.value_of("working_dir").map(|x| x.to_string()),
+                    ..Default::default()
+                })
+            } else {
+                None
+            },
         }
     } else {
         eprintln!("invalid duration");
@@ -140,14 +227,58 @@ fn get_env() -> (Vec<String>, Vec<String>) {
     (a, b)
 }
 
+fn get_captures(functions: &[String]) -> Vec<String> {
+    let specs = get_multiple("capture");
+    if specs.is_empty() {
+        return Vec::new();
+    }
+    let mut captures = vec![String::new(); functions.len()];
+    for i in specs {
+        let mut split = i.splitn(2, '=');
+        let (func, spec) = (split.next().unwrap().trim(), split.next().unwrap_or(""));
+        match functions.iter().position(|x| x == func) {
+            Some(k) => captures[k] = spec.trim().to_string(),
+            None => {
+                eprintln!("[ERROR] capture of unknown function: {}", func);
+                std::process::exit(1);
+            }
+        }
+    }
+    captures
+}
+
+fn get_templates() -> (Vec<String>, Vec<String>) {
+    let mut types = Vec::new();
+    let mut templates = Vec::new();
+    for i in get_multiple("template") {
+        let mut split = i.splitn(2, '=');
+        let (t, path) = (split.next().unwrap().trim(), split.next().unwrap_or(""));
+        let mut body = String::new();
+        if let Err(e) = File::open(path.trim()).and_then(|mut x| x.read_to_string(&mut body)) {
+            eprintln!("[ERROR] cannot read template {}: {}", path, e);
+            std::process::exit(1);
+        }
+        types.push(t.to_string());
+        templates.push(body);
+    }
+    (types, templates)
+}
+
 pub fn get_trace() -> PutTrace {
     let (a, b) = get_env();
+    let function_list = get_multiple("function");
+    let captures = get_captures(function_list.as_slice());
+    let (template_types, templates) = get_templates();
     PutTrace {
         process: SUB_COMMAND.1.value_of("process").unwrap().to_string(),
-        function_list: get_multiple("function"),
+        function_list,
         environment: a,
         values: b,
         options: get_multiple("option"),
+        captures,
+        template_types,
+        templates,
+        kernel_probes: get_multiple("kernel"),
     }
 }
 
@@ -157,4 +288,37 @@ lazy_static! {
 
 pub fn config() -> &'static str {
     *CONFIG
-}
\ No newline at end of file
+}
+
+pub fn get_check() -> bool {
+    SUB_COMMAND.1.is_present("check")
+}
+
+pub fn get_uid() -> u32 {
+    match SUB_COMMAND.1.value_of("uid").map(|x| x.parse()) {
+        Some(Ok(t)) => t,
+        _ => {
+            eprintln!("invalid uid");
+            std::process::exit(1)
+        }
+    }
+}
+
+pub fn get_limit() -> i64 {
+    match SUB_COMMAND.1.value_of("limit").map(|x| x.parse()) {
+        None => 100,
+        Some(Ok(t)) => t,
+        Some(Err(_)) => {
+            eprintln!("invalid limit");
+            std::process::exit(1)
+        }
+    }
+}
+
+pub fn get_name() -> &'static str {
+    SUB_COMMAND.1.value_of("name").unwrap()
+}
+
+pub fn get_scope() -> Scope {
+    SUB_COMMAND.1.value_of("scope").and_then(|x| x.parse().ok()).unwrap()
+}
@@ -14,20 +14,166 @@ pub struct DataBaseConfig {
     pub password: String,
     pub database: String
 }
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryMode {
+    /// Chunks are submitted to `platform_url`.
+    #[default]
+    Push,
+    /// Chunks stay in the spool until the platform fetches and acknowledges them, the endpoint
+    /// makes no outbound calls.
+    Pull,
+}
+
+/// How the tracers get the privileges they need.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivilegeMode {
+    /// `sudo -n`, relying on a sudoers rule that only allows the tracer binaries, e.g.
+    /// `lambda ALL=(root) NOPASSWD: /usr/bin/bpftrace, /usr/bin/stap`.
+    /// PERF cannot be used safely in this mode: its scripts run with `/bin/sh`, and allowing that
+    /// grants root to anyone able to write a script. Use the helper for PERF.
+    Sudo,
+    /// Run the tracers directly, their binaries having been given the capabilities they need,
+    /// e.g. `setcap cap_bpf,cap_perfmon,cap_sys_admin+ep /usr/bin/bpftrace`.
+    Capabilities,
+    /// Ask the privileged helper (`lambda-endpoint helper`) listening at `helper_socket`.
+    Helper,
+    /// Pipe `root_password` into `sudo -S`, deprecated.
+    SudoPassword,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GlobalConfig {
-    pub root_password : String,
+    /// Deprecated, only used by the `sudo_password` privilege mode.
+    #[serde(default)]
+    pub root_password : Option<String>,
+    /// Defaults to `sudo_password` if a root password is configured, `sudo` otherwise.
+    #[serde(default)]
+    pub privilege: Option<PrivilegeMode>,
+    #[serde(default = "default_helper_socket")]
+    pub helper_socket: String,
     pub bpf_path : String,
     pub stap_path : String,
+    #[serde(default = "default_perf_path")]
+    pub perf_path : String,
+    /// Accept custom probe templates. A template is tracer code run with the privileges of the
+    /// tracer, anyone able to put a trace can then run commands as root.
+    #[serde(default)]
+    pub allow_templates: bool,
     pub submit_chunk_size: usize,
+    /// Milliseconds before output that does not fill a chunk is submitted anyway.
+    #[serde(default = "default_submit_max_latency")]
+    pub submit_max_latency: u64,
+    #[serde(default = "default_submit_queue_size")]
+    pub submit_queue_size: usize,
+    /// Seconds before a submission to the platform is given up and left to the retrier.
+    #[serde(default = "default_submit_timeout")]
+    pub submit_timeout: u64,
+    /// Compressions to use when the platform accepts them, most preferred first.
+    #[serde(default = "default_submit_encodings")]
+    pub submit_encodings: Vec<String>,
+    /// Bytes of submissions sent in one request when the platform accepts batches.
+    #[serde(default = "default_submit_batch_size")]
+    pub submit_batch_size: usize,
+    /// Milliseconds a chunk waits for the following ones to join its batch.
+    #[serde(default = "default_submit_batch_delay")]
+    pub submit_batch_delay: u64,
+    /// Seconds a tracer may outlive its duration before it is killed as timed out.
+    #[serde(default = "default_stop_grace_period")]
+    pub stop_grace_period: u64,
+    #[serde(default = "default_spool_dir")]
+    pub spool_dir: String,
+    /// Seconds the spooled output of a run is kept once all of it was delivered.
+    #[serde(default = "default_spool_retention")]
+    pub spool_retention: u64,
     pub platform_url : String,
+    #[serde(default)]
+    pub delivery_mode: DeliveryMode,
     pub secret: String,
+    /// Seconds a signed request stays valid, in either direction to allow for clock skew.
+    #[serde(default = "default_auth_skew")]
+    pub auth_skew: u64,
+    /// Routes served without authentication, as `METHOD /path`.
+    #[serde(default)]
+    pub public_routes: Vec<String>,
     pub endpoint_uuid: String,
     pub listen_address: String,
     pub listen_port: u16,
+    /// PEM certificate chain and private key, HTTPS is served when both are set.
+    #[serde(default)]
+    pub tls_cert: Option<String>,
+    #[serde(default)]
+    pub tls_key: Option<String>,
+    /// PEM certificates of the CA that signs the certificates of the clients, which are then
+    /// required.
+    #[serde(default)]
+    pub tls_client_ca: Option<String>,
+    /// PEM certificate of the CA that signs the certificate of the platform, the only one trusted
+    /// when set.
+    #[serde(default)]
+    pub platform_ca: Option<String>,
+    /// Address announced to the platform, defaults to the listening address.
+    #[serde(default)]
+    pub advertise_url: Option<String>,
+    /// Seconds between two heartbeats sent to the platform.
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
     pub database_config: DataBaseConfig
 }
 
+fn default_auth_skew() -> u64 {
+    300
+}
+
+fn default_helper_socket() -> String {
+    "/run/lambda-endpoint/helper.sock".to_string()
+}
+
+fn default_perf_path() -> String {
+    "perf".to_string()
+}
+
+fn default_submit_max_latency() -> u64 {
+    1000
+}
+
+fn default_submit_queue_size() -> usize {
+    16
+}
+
+fn default_submit_timeout() -> u64 {
+    30
+}
+
+fn default_submit_encodings() -> Vec<String> {
+    vec!["zstd".to_string(), "gzip".to_string()]
+}
+
+fn default_submit_batch_size() -> usize {
+    1 << 20
+}
+
+fn default_submit_batch_delay() -> u64 {
+    200
+}
+
+fn default_stop_grace_period() -> u64 {
+    30
+}
+
+fn default_heartbeat_interval() -> u64 {
+    30
+}
+
+fn default_spool_dir() -> String {
+    "/var/lib/lambda-endpoint/spool".to_string()
+}
+
+fn default_spool_retention() -> u64 {
+    7 * 24 * 3600
+}
+
 fn init_config() -> GlobalConfig {
     let config = config();
     let mut buffer = String::new();
@@ -43,13 +189,27 @@ fn init_config() -> GlobalConfig {
     }
 }
 
+impl GlobalConfig {
+    pub fn tls(&self) -> bool {
+        self.tls_cert.is_some() && self.tls_key.is_some()
+    }
+
+    pub fn privilege(&self) -> PrivilegeMode {
+        self.privilege.unwrap_or(if self.root_password.is_some() {
+            PrivilegeMode::SudoPassword
+        } else {
+            PrivilegeMode::Sudo
+        })
+    }
+}
+
 lazy_static!{
     static ref GLOBAL : GlobalConfig = init_config();
     static ref ADDR : String = format!("{}:{}", GLOBAL.listen_address, GLOBAL.listen_port);
 }
 
 pub fn global_config() -> &'static GlobalConfig {
-    &*GLOBAL
+    &GLOBAL
 }
 
 pub fn address() -> &'static str {
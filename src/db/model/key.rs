@@ -0,0 +1,121 @@
+use chrono::{DateTime, Utc};
+use crypto_api_osrandom::OsRandom;
+use diesel::prelude::*;
+use serde::*;
+
+use crate::db::connection::get_conn;
+use crate::db::schema::key::api_keys;
+use crate::db::schema::key::api_keys::dsl::*;
+
+/// What an API key may do, every scope includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// The traces, the running tracers, the state of the endpoint and the runs.
+    Read,
+    /// Start and kill tracers.
+    Run,
+    /// Put and delete traces.
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Run => "run",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "run" => Ok(Scope::Run),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!("invalid scope: {}", s))
+        }
+    }
+}
+
+#[derive(Queryable, Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub scope: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "api_keys"]
+struct NewApiKey<'a> {
+    name: &'a str,
+    secret: &'a str,
+    scope: &'a str,
+    created_at: DateTime<Utc>,
+}
+
+// the name is sent in the authorization header, as a `key=value` pair among others split on `,`
+fn is_valid_name(key: &str) -> bool {
+    !key.is_empty() && !key.chars().any(|x| x == ',' || x == '=' || x.is_whitespace())
+}
+
+impl ApiKey {
+    /// A new key with a random secret, the secret signs the requests like the one of the endpoint.
+    pub fn create(key: &str, s: Scope) -> Result<ApiKey, String> {
+        if !is_valid_name(key) {
+            return Err(format!("invalid key name: {:?}", key));
+        }
+        let mut random = [0_u8; 32];
+        OsRandom::secure_rng().random(&mut random).expect("unable to gen secret");
+        let random = hex::encode(random);
+        let conn = get_conn();
+        diesel::insert_into(api_keys::table)
+            .values(&NewApiKey {
+                name: key,
+                secret: random.as_str(),
+                scope: s.as_str(),
+                created_at: Utc::now(),
+            })
+            .get_result(&*conn)
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn list() -> QueryResult<Vec<ApiKey>> {
+        let conn = get_conn();
+        api_keys.order(created_at.asc()).load(&*conn)
+    }
+
+    /// The key named `key`, unless it was revoked.
+    pub fn find(key: &str) -> QueryResult<Option<ApiKey>> {
+        let conn = get_conn();
+        api_keys.filter(name.eq(key)).filter(revoked_at.is_null()).first(&*conn).optional()
+    }
+
+    pub fn revoke(key: &str) -> QueryResult<usize> {
+        let conn = get_conn();
+        diesel::update(api_keys.filter(name.eq(key)).filter(revoked_at.is_null()))
+            .set(revoked_at.eq(Some(Utc::now())))
+            .execute(&*conn)
+    }
+
+    /// Keys with an unknown scope grant nothing.
+    pub fn scope(&self) -> Option<Scope> {
+        self.scope.parse().ok()
+    }
+}
+
+#[test]
+fn key_names() {
+    assert!(is_valid_name("ci-runner_1"));
+    assert!(!is_valid_name(""));
+    assert!(!is_valid_name("a,signature=x"));
+    assert!(!is_valid_name("a b"));
+    assert!(!is_valid_name("a\tb"));
+}
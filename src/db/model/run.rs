@@ -0,0 +1,100 @@
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::*;
+
+use crate::db::connection::get_conn;
+use crate::db::schema::run::trace_runs;
+use crate::db::schema::run::trace_runs::dsl::*;
+
+#[derive(Queryable, Debug, Clone, Serialize, Deserialize)]
+pub struct TraceRun {
+    pub id: i32,
+    pub trace_id: i32,
+    pub trace_type: String,
+    pub script: String,
+    pub started_by: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub exit_status: Option<String>,
+    pub stderr: Option<String>,
+    pub chunks: i32,
+    pub bytes: i64,
+}
+
+/// How a run ended, as stored in `exit_status` and reported in the last submission.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    /// The tracer exited successfully.
+    Completed,
+    Killed,
+    /// The tracer could not be started, exited with an error or its output could not be read.
+    Failed,
+    /// The tracer outlived its duration and was killed by the endpoint.
+    TimedOut,
+}
+
+impl RunStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RunStatus::Completed => "completed",
+            RunStatus::Killed => "killed",
+            RunStatus::Failed => "failed",
+            RunStatus::TimedOut => "timed_out",
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "trace_runs"]
+struct NewTraceRun<'a> {
+    trace_id: i32,
+    trace_type: &'a str,
+    script: &'a str,
+    started_by: &'a str,
+    start_time: DateTime<Utc>,
+}
+
+impl TraceRun {
+    pub fn start(trace: i32, t: &str, path: &str, by: &str) -> QueryResult<TraceRun> {
+        let conn = get_conn();
+        diesel::insert_into(trace_runs::table)
+            .values(&NewTraceRun {
+                trace_id: trace,
+                trace_type: t,
+                script: path,
+                started_by: by,
+                start_time: Utc::now(),
+            })
+            .get_result(&*conn)
+    }
+
+    pub fn record_chunk(run: i32, size: usize) -> QueryResult<usize> {
+        let conn = get_conn();
+        diesel::update(trace_runs.filter(id.eq(run)))
+            .set((chunks.eq(chunks + 1), bytes.eq(bytes + size as i64)))
+            .execute(&*conn)
+    }
+
+    /// Only the first status reported for a run is kept.
+    pub fn finish(run: i32, status: RunStatus, err: Option<String>) -> QueryResult<usize> {
+        let conn = get_conn();
+        diesel::update(trace_runs.filter(id.eq(run)).filter(end_time.is_null()))
+            .set((end_time.eq(Some(Utc::now())), exit_status.eq(Some(status.as_str())), stderr.eq(err)))
+            .execute(&*conn)
+    }
+
+    pub fn finish_some(run: Option<i32>, status: RunStatus, err: Option<String>) -> QueryResult<usize> {
+        run.map(|x| Self::finish(x, status, err)).unwrap_or(Ok(0))
+    }
+
+    /// Most recent runs first, optionally only those of one trace.
+    pub fn history(trace: Option<i32>, limit: i64) -> QueryResult<Vec<TraceRun>> {
+        let conn = get_conn();
+        let mut query = trace_runs.into_boxed();
+        if let Some(t) = trace {
+            query = query.filter(trace_id.eq(t));
+        }
+        query.order(start_time.desc()).limit(limit).load(&*conn)
+    }
+}
@@ -1,19 +1,24 @@
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
-use std::process::{ChildStderr, ChildStdout, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 use chrono::Utc;
 use diesel::*;
+use diesel::result::QueryResult;
 use rayon::prelude::*;
 use serde::*;
 use tokio::prelude::*;
 use uuid::Uuid;
 
-use crate::endpoint::{remove_running, RunningTrace};
-use crate::http_client::submit;
+use crate::db::model::run::{RunStatus, TraceRun};
+use crate::endpoint::{kill_running, remove_running, RunningTrace};
+use crate::event::{Chunker, EventParser};
+use crate::http_client::Submission;
+use crate::tracer::{RunOptions, TracerBackend};
 
-#[derive(Queryable, Debug, Serialize, Deserialize)]
+#[derive(Queryable, Debug, Clone, Serialize, Deserialize)]
 pub struct Trace {
     pub id: i32,
     pub process: String,
@@ -21,140 +26,344 @@ pub struct Trace {
     pub environment: Vec<String>,
     pub values: Vec<String>,
     pub options: Vec<String>,
+    pub captures: Vec<String>,
+    pub template_types: Vec<String>,
+    pub templates: Vec<String>,
+    pub kernel_probes: Vec<String>,
 }
 
-fn submit_step(mut k: usize, mut stdout: ChildStdout, mut stderr: ChildStderr, name: String, mut buffer: Vec<u8>) {
-    k += 1;
-    match stdout.read(buffer.as_mut()) {
-        Ok(n) => if n == buffer.len() {
-            submit(name.clone(), &buffer[0..n], false, None, k);
-            tokio::spawn(futures::future::lazy(move || Ok(
-                submit_step(k, stdout, stderr, name, buffer))));
-        } else {
-            let stderr = Some({
-                let mut b = String::new();
-                stderr.read_to_string(&mut b).expect("failed to get stderr");
-                b
-            });
-            submit(name.clone(), &buffer[0..n], true, stderr, k);
-            println!("[INFO] all submissions of {} finished.", name);
-            remove_running(name.as_str());
-        },
-        Err(e) => {
-            eprintln!("[ERROR] error encountered when running {}: {}", name, e);
-            remove_running(name.as_str());
+/// A value to be printed when a traced function is hit.
+/// Accepts both the SystemTap (`$arg1`, `$return`) and the bpftrace (`arg0`, `retval`) spelling;
+/// argument indices are stored zero-based.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    Arg(usize),
+    Return,
+}
+
+impl std::str::FromStr for Capture {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s {
+            "$return" | "retval" => Ok(Capture::Return),
+            _ if s.starts_with("$arg") => match s[4..].parse::<usize>() {
+                Ok(n) if n > 0 => Ok(Capture::Arg(n - 1)),
+                _ => Err(format!("invalid capture: {}", s))
+            },
+            _ if s.starts_with("arg") => s[3..].parse::<usize>()
+                .map(Capture::Arg)
+                .map_err(|_| format!("invalid capture: {}", s)),
+            _ => Err(format!("invalid capture: {}", s))
         }
     }
 }
-macro_rules! template {
-    ("STAP") => {
-r#"
-probe process("{}").function("{}").call {{
-    printf("probe: %s", ppfunc());
-    print_usyms(ucallers(-1));
-}}
-"#
-};
-    ("BPF") => {
-r#"
-uprobe:{}:{} {{
-    printf("probe: %s\n%s\n", probe, ustack(perf, 5));
-}}
-"#
-    };
+
+/// A kernel side probe recorded alongside the functions of a trace, written as
+/// `tracepoint:<category>:<name>`, `kprobe:<function>` or `syscall:<name>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KernelProbe {
+    Tracepoint(String, String),
+    Kprobe(String),
+    Syscall(String),
+}
+
+impl std::str::FromStr for KernelProbe {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // the names end up in scripts, only allow what the tracers accept as probe names
+        let valid = |x: &str| !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '*');
+        let parts = s.trim().split(':').collect::<Vec<_>>();
+        match parts.as_slice() {
+            ["tracepoint", category, name] if valid(category) && valid(name) =>
+                Ok(KernelProbe::Tracepoint(category.to_string(), name.to_string())),
+            ["kprobe", function] if valid(function) => Ok(KernelProbe::Kprobe(function.to_string())),
+            ["syscall", name] if valid(name) => Ok(KernelProbe::Syscall(name.to_string())),
+            _ => Err(format!("invalid kernel probe: {}", s))
+        }
+    }
+}
+
+/// Placeholders available in custom probe templates.
+pub const PLACEHOLDERS: [&str; 4] = ["process", "function", "index", "trace_id"];
+
+/// Constructs of the tracer languages that reach outside of the probes: embedded C for stap,
+/// commands for both stap and bpftrace.
+const FORBIDDEN: [&str; 2] = ["%{", "%}"];
+const FORBIDDEN_CALLS: [&str; 1] = ["system"];
+
+/// Check that every `${...}` of a custom probe template is a known placeholder, and that it uses
+/// none of the constructs running code outside of the tracer.
+pub fn validate_template(template: &str) -> Result<(), String> {
+    if let Some(x) = FORBIDDEN.iter().find(|x| template.contains(*x)) {
+        return Err(format!("forbidden construct in template: {}", x));
+    }
+    if let Some(x) = template.split(|x: char| !x.is_ascii_alphanumeric() && x != '_')
+        .find(|x| FORBIDDEN_CALLS.contains(x)) {
+        return Err(format!("forbidden call in template: {}", x));
+    }
+    let mut rest = template;
+    while let Some(n) = rest.find("${") {
+        rest = &rest[n + 2..];
+        let end = rest.find('}').ok_or_else(|| "unclosed placeholder in template".to_string())?;
+        if !PLACEHOLDERS.contains(&&rest[..end]) {
+            return Err(format!("unknown placeholder in template: ${{{}}}", &rest[..end]));
+        }
+        rest = &rest[end + 1..];
+    }
+    Ok(())
+}
+
+/// Parse a comma separated capture spec such as `arg0,arg1,retval`.
+pub fn parse_captures(spec: &str) -> Result<Vec<Capture>, String> {
+    spec.split(',')
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| x.parse())
+        .collect()
 }
 
-fn ending(s: &str, t: usize) -> String {
-    match s {
-        "STAP" =>
-            format!("probe timer.s({}) {{exit(); }}\n", t),
-        "BPF" =>
-            format!("interval:s:{} {{ exit(); }}\n", t),
-        _ => unreachable!()
+fn record_run<T>(run: Option<i32>, f: impl FnOnce(i32) -> QueryResult<T>) {
+    if let Some(Err(e)) = run.map(f) {
+        eprintln!("[ERROR] failed to record run: {}", e);
+    }
+}
+
+// reads the tracer output on its own thread and hands it over to the chunker through a channel,
+// so that a partial chunk can be flushed while the read is still blocked
+fn read_output(mut stdout: Box<dyn Read + Send>, size: usize) -> Receiver<std::io::Result<Vec<u8>>> {
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        let mut buffer = vec![0_u8; size];
+        loop {
+            match stdout.read(buffer.as_mut()) {
+                Ok(0) => break,
+                Ok(n) => if sender.send(Ok(buffer[0..n].to_vec())).is_err() { break },
+                Err(e) => {
+                    sender.send(Err(e)).unwrap_or(());
+                    break;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+// waits for the tracer to exit, the status set when the endpoint killed it takes precedence
+fn reap(name: &str) -> (RunStatus, Option<i32>, Option<String>) {
+    match remove_running(name) {
+        Some(mut t) => match t.child.wait() {
+            Ok(status) => {
+                let outcome = if status.success() { RunStatus::Completed } else { RunStatus::Failed };
+                (t.outcome.unwrap_or(outcome), status.code(), None)
+            }
+            Err(e) => (t.outcome.unwrap_or(RunStatus::Failed), None, Some(e.to_string()))
+        },
+        None => (RunStatus::Failed, None, Some("tracer is no longer tracked".to_string()))
+    }
+}
+
+// runs on its own thread until the tracer closes its output, handing over a chunk blocks while
+// the submission queue is full; a tracer still running past `deadline` is killed
+fn submit_output(stdout: Box<dyn Read + Send>, mut stderr: Box<dyn Read + Send>, name: String, mut submission: Submission,
+                 run: Option<i32>, mut deadline: Option<Instant>) {
+    let config = crate::config::global_config();
+    let latency = Duration::from_millis(config.submit_max_latency);
+    let output = read_output(stdout, config.submit_chunk_size);
+    let key = crate::spool::run_key(run, name.as_str());
+    // drained alongside stdout so that a verbose tracer cannot block on a full pipe
+    let errors = std::thread::spawn(move || {
+        let mut b = String::new();
+        stderr.read_to_string(&mut b).map(|_| b)
+    });
+    let mut chunker = Chunker::new(config.submit_chunk_size);
+    let mut parser = EventParser::new();
+    let mut failure = None;
+    let mut k = 0;
+    // when the oldest byte not yet submitted was read
+    let mut since: Option<Instant> = None;
+    loop {
+        let now = Instant::now();
+        let wait = since.map(|t| latency.checked_sub(t.elapsed()).unwrap_or_default())
+            .into_iter()
+            .chain(deadline.map(|d| d.checked_duration_since(now).unwrap_or_default()))
+            .min();
+        let received = match wait {
+            Some(w) => output.recv_timeout(w),
+            None => output.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let chunks = match received {
+            Ok(Ok(data)) => {
+                crate::live::publish_output(key.as_str(), &data);
+                chunker.feed(&data)
+            }
+            Ok(Err(e)) => {
+                eprintln!("[ERROR] error encountered when running {}: {}", name, e);
+                kill_running(name.as_str(), RunStatus::Failed);
+                failure = Some(e.to_string());
+                break;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if deadline.map(|d| d <= Instant::now()).unwrap_or(false) {
+                    eprintln!("[ERROR] {} did not stop in time, killing it", name);
+                    kill_running(name.as_str(), RunStatus::TimedOut);
+                    deadline = None;
+                }
+                if since.map(|t| t.elapsed() >= latency).unwrap_or(false) {
+                    // what a flush leaves, such as a partial record, waits for the next deadline
+                    since = Some(Instant::now());
+                    chunker.flush().into_iter().collect()
+                } else {
+                    Vec::new()
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if chunker.is_empty() {
+            since = None;
+        } else if since.is_none() || !chunks.is_empty() {
+            since = Some(Instant::now());
+        }
+        for chunk in chunks {
+            k += 1;
+            submission.chunk(&chunk, parser.feed(&chunk), k);
+            record_run(run, |x| TraceRun::record_chunk(x, chunk.len()));
+        }
     }
+    let (status, code, error) = reap(name.as_str());
+    let stderr = match errors.join() {
+        Ok(Ok(b)) => b,
+        Ok(Err(e)) => format!("failed to get stderr: {}", e),
+        Err(_) => "failed to get stderr".to_string(),
+    };
+    let stderr = Some(failure.into_iter().chain(error).fold(stderr, |acc, x| acc + x.as_str() + "\n"));
+    let rest = chunker.finish();
+    let mut events = parser.feed(&rest);
+    events.extend(parser.finish());
+    k += 1;
+    submission.finish(&rest, events, status, code, stderr.clone(), k);
+    record_run(run, |x| TraceRun::record_chunk(x, rest.len()));
+    record_run(run, |x| TraceRun::finish(x, status, stderr));
+    crate::live::close(key.as_str(), status.as_str());
+    println!("[INFO] all submissions of {} finished: {}", name, status.as_str());
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceMode {
+    /// Print every hit together with its user stack.
+    #[default]
+    Stack,
+    /// Pair entry and return probes per thread and aggregate the durations into log2 histograms.
+    Latency,
 }
 
+impl std::str::FromStr for TraceMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stack" => Ok(TraceMode::Stack),
+            "latency" => Ok(TraceMode::Latency),
+            _ => Err(format!("invalid trace mode: {}", s))
+        }
+    }
+}
 
 impl Trace {
-    pub fn to_content_stap(&self) -> String {
-        self.function_list.par_iter().map(|x| {
-            format!(template!("STAP"), self.process, x)
-        }).reduce_with(|mut x, y| {
-            x.push_str(y.as_str());
-            x
-        }).unwrap()
+    /// Captures of the function at `index`; traces without capture specs capture nothing.
+    pub fn captures_of(&self, index: usize) -> Vec<Capture> {
+        self.captures.get(index)
+            .and_then(|x| parse_captures(x.as_str()).ok())
+            .unwrap_or_default()
+    }
+    pub fn kernel_probes(&self) -> Vec<KernelProbe> {
+        self.kernel_probes.iter().filter_map(|x| x.parse().ok()).collect()
     }
-    pub fn to_content_bpf(&self) -> String {
-        self.function_list.par_iter().map(|x| {
-            format!(template!("BPF"), self.process, x)
+    /// Name of the traced process as seen by the kernel, used to filter kernel probes.
+    pub fn comm(&self) -> String {
+        let name = self.process.rsplit('/').next().unwrap_or("");
+        name.chars().take(15).collect()
+    }
+    pub fn template_of(&self, backend: &str) -> Option<&str> {
+        self.template_types.iter()
+            .position(|x| x == backend)
+            .and_then(|x| self.templates.get(x))
+            .map(|x| x.as_str())
+    }
+    pub fn render_template(&self, template: &str, index: usize, function: &str) -> String {
+        template.replace("${process}", self.process.as_str())
+            .replace("${function}", function)
+            .replace("${index}", index.to_string().as_str())
+            .replace("${trace_id}", self.id.to_string().as_str())
+    }
+    pub fn to_content(&self, backend: &dyn TracerBackend, run: &RunOptions) -> String {
+        // custom templates replace the stack probes, the other modes keep their own aggregation
+        let template = self.template_of(backend.name()).filter(|_| run.mode == TraceMode::Stack);
+        let mut content = backend.prologue(self, run);
+        content.push_str(self.function_list.par_iter().enumerate().map(|(i, x)| {
+            match template {
+                Some(t) => backend.template_probe(self.process.as_str(), x, self.render_template(t, i, x).as_str(), run)
+                    .unwrap_or_default(),
+                None => backend.probe(self, i, x, run)
+            }
         }).reduce_with(|mut x, y| {
             x.push_str(y.as_str());
             x
-        }).unwrap()
+        }).unwrap_or_default().as_str());
+        // rejected by `tracer::check` in the latency mode
+        if run.mode == TraceMode::Stack {
+            for (i, x) in self.kernel_probes().iter().enumerate() {
+                content.push_str(backend.kernel_probe(self, i, x, run).unwrap_or_default().as_str());
+            }
+        }
+        content.push_str(backend.epilogue(self, run).as_str());
+        content
     }
-    pub fn to_file_stap(&self, duration: usize) -> std::io::Result<String> {
-        let content = self.to_content_stap();
-        let name = format!("/tmp/{}.stap", Uuid::new_v4());
-        let mut file = File::create(name.as_str())?;
-        file.write(content.as_bytes())?;
-        file.write(ending("STAP", duration).as_bytes())?;
-        file.flush()?;
-        Ok(name)
+    /// The exact script run by `backend`.
+    pub fn to_script(&self, backend: &dyn TracerBackend, run: &RunOptions) -> String {
+        let mut content = self.to_content(backend, run);
+        content.push_str(backend.termination(run).as_str());
+        content
     }
-    pub fn to_file_bpf(&self, duration: usize) -> std::io::Result<String> {
-        let content = self.to_content_bpf();
-        let name = format!("/tmp/{}.bpf", Uuid::new_v4());
+    pub fn to_file(&self, backend: &dyn TracerBackend, run: &RunOptions) -> std::io::Result<String> {
+        let content = self.to_script(backend, run);
+        let name = format!("/tmp/{}.{}", Uuid::new_v4(), backend.extension());
         let mut file = File::create(name.as_str())?;
-        file.write(content.as_bytes())?;
-        file.write(ending("BPF", duration).as_bytes())?;
+        file.write_all(content.as_bytes())?;
         file.flush()?;
+        crate::tracer::write_launcher(name.as_str(), self, run)?;
         Ok(name)
     }
 
-    pub fn run(&self, duration: usize, t: &str) -> String {
+    /// Start the trace in the background, returns the path of the script and the id of the recorded run.
+    pub fn run(&self, backend: &'static dyn TracerBackend, run: RunOptions, started_by: &str) -> (String, Option<i32>) {
         let mut name = String::new();
-        let script = if t == "STAP" {
-            self.to_file_stap(duration)
-                .map(|x| {
-                    name.clone_from(&x);
-                    x
-                }).map_err(|x|
-                eprintln!("failed to generate stap file: {}", x)
-            ).into_future()
-        } else {
-            self.to_file_bpf(duration)
-                .map(|x| {
-                    name.clone_from(&x);
-                    x
-                }).map_err(|x|
-                eprintln!("failed to generate stap file: {}", x)
-            ).into_future()
-        };
-        let flag = t == "STAP";
+        let script = self.to_file(backend, &run)
+            .map(|x| {
+                name.clone_from(&x);
+                x
+            }).map_err(move |x|
+            eprintln!("failed to generate {} file: {}", backend.extension(), x)
+        ).into_future();
         let id = self.id;
         let _name = name.clone();
-        let envs =
-            self.environment.iter().cloned().zip(self.values.iter().cloned()).collect::<Vec<(String, String)>>();
-        let args = self.options.clone();
+        let trace = self.clone();
+        let run_id = match TraceRun::start(id, backend.name(), name.as_str(), started_by) {
+            Ok(r) => Some(r.id),
+            Err(e) => {
+                eprintln!("[ERROR] failed to record run: {}", e);
+                None
+            }
+        };
+        if name.is_empty() {
+            record_run(run_id, |x| TraceRun::finish(x, RunStatus::Failed, Some("failed to generate script".to_string())));
+        }
         let f = script.and_then(move |x| {
-            crate::http_client::submit_start(x.clone());
-            let child = std::process::Command::new("sudo")
-                .arg("-S")
-                .arg(if flag { crate::config::global_config().stap_path.as_str() } else { crate::config::global_config().bpf_path.as_str() })
-                .arg(x.as_str())
-                .args(args)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .stdin(Stdio::piped())
-                .envs(envs)
-                .spawn();
-            match child {
+            let key = crate::spool::run_key(run_id, x.as_str());
+            crate::live::open(key.as_str());
+            let mut submission = Submission::open(key.clone(), x.clone());
+            submission.start();
+            match crate::tracer::spawn(backend, x.as_str(), &trace, &run) {
                 Ok(mut child) => {
-                    {
-                        let mut input = child.stdin.take().expect("unable to get input");
-                        input.write(crate::config::global_config().root_password.as_bytes()).unwrap();
-                        input.flush().unwrap();
-                    }
                     let output =
                         child.stdout.take().expect("unable to get output");
                     let stderr =
@@ -162,41 +371,89 @@ impl Trace {
                     let rt = RunningTrace {
                         start_time: Utc::now(),
                         trace_id: id,
+                        run_id,
                         child,
+                        outcome: None,
                     };
                     crate::endpoint::put_running(_name.as_str(), rt);
-                    let mut buffer = Vec::new();
-
-                    buffer.resize(crate::config::global_config().submit_chunk_size, 0_u8);
-                    submit_step(0, output, stderr, _name, buffer);
+                    crate::live::publish_status(key.as_str(), "running");
+                    // a launched process may run for as long as it needs
+                    let deadline = match run.launch {
+                        Some(_) => None,
+                        None => Some(Instant::now() + Duration::from_secs(
+                            run.duration as u64 + crate::config::global_config().stop_grace_period)),
+                    };
+                    std::thread::spawn(move || submit_output(output, stderr, _name, submission, run_id, deadline));
                     Ok(())
                 }
                 Err(e) => {
                     eprintln!("unable to spawn process: {}", e);
+                    crate::live::close(key.as_str(), RunStatus::Failed.as_str());
+                    submission.finish(&[], Vec::new(), RunStatus::Failed, None, Some(e.to_string()), 1);
+                    record_run(run_id, |x| TraceRun::finish(x, RunStatus::Failed, Some(e.to_string())));
                     Err(())
                 }
             }
         });
         tokio::spawn(f);
 
-        name
+        (name, run_id)
     }
 }
 
 #[cfg(test)]
 mod test {
+    #[test]
+    fn test_parse_captures() {
+        use super::{Capture, parse_captures};
+        assert_eq!(parse_captures("arg0, $arg2,retval").unwrap(),
+                   vec![Capture::Arg(0), Capture::Arg(1), Capture::Return]);
+        assert_eq!(parse_captures("$return").unwrap(), vec![Capture::Return]);
+        assert!(parse_captures("").unwrap().is_empty());
+        assert!(parse_captures("$arg0").is_err());
+        assert!(parse_captures("argv").is_err());
+    }
+
+    #[test]
+    fn test_parse_kernel_probes() {
+        use super::KernelProbe;
+        assert_eq!("tracepoint:sched:sched_*".parse::<KernelProbe>().unwrap(),
+                   KernelProbe::Tracepoint("sched".to_string(), "sched_*".to_string()));
+        assert_eq!("kprobe:do_sys_open".parse::<KernelProbe>().unwrap(),
+                   KernelProbe::Kprobe("do_sys_open".to_string()));
+        assert_eq!("syscall:openat".parse::<KernelProbe>().unwrap(),
+                   KernelProbe::Syscall("openat".to_string()));
+        assert!("syscall:open\")".parse::<KernelProbe>().is_err());
+        assert!("uprobe:/bin/sh:main".parse::<KernelProbe>().is_err());
+    }
+
+    #[test]
+    fn test_validate_template() {
+        use super::validate_template;
+        assert!(validate_template("printf(\"%s\\n\", \"${function}\"); @[${index}] = count();").is_ok());
+        assert!(validate_template("if (pid() == 1) { exit() }").is_ok());
+        assert!(validate_template("${func}").is_err());
+        assert!(validate_template("${process").is_err());
+        assert!(validate_template("system(\"id\");").is_err());
+        assert!(validate_template("system (\"id\")").is_err());
+        assert!(validate_template("%{ setuid(0); %}").is_err());
+        assert!(validate_template("@[probe] = count(); // filesystem").is_ok());
+    }
+
     #[test]
     fn test_to_content() {
         use std::fs::File;
         use std::io::Write;
         use crate::db::schema::trace::traces::dsl::*;
         use diesel::prelude::*;
-        use super::Trace;
+        use crate::tracer::{BpfTrace, RunOptions, SystemTap};
+        use super::{Trace, TraceMode};
         let conn = crate::db::connection::get_conn();
         let res = traces.load::<Trace>(&*conn).unwrap();
         let mut file = File::create("/tmp/cargo_test").unwrap();
         for i in res {
-            writeln!(file, "{}", i.to_content_stap()).unwrap();
+            writeln!(file, "{}", i.to_content(&SystemTap, &RunOptions::new(5, TraceMode::Stack))).unwrap();
+            writeln!(file, "{}", i.to_content(&BpfTrace, &RunOptions::new(5, TraceMode::Latency))).unwrap();
         }
     }
 
@@ -204,11 +461,13 @@ mod test {
     fn test_to_file() {
         use crate::db::schema::trace::traces::dsl::*;
         use diesel::prelude::*;
-        use super::Trace;
+        use crate::tracer::{RunOptions, SystemTap};
+        use super::{Trace, TraceMode};
         let conn = crate::db::connection::get_conn();
         let res = traces.load::<Trace>(&*conn).unwrap();
         for i in res {
-            i.to_file_stap(5).unwrap();
+            i.to_file(&SystemTap, &RunOptions::new(5, TraceMode::Stack)).unwrap();
+            i.to_file(&SystemTap, &RunOptions::new(5, TraceMode::Latency)).unwrap();
         }
     }
 
@@ -217,12 +476,13 @@ mod test {
         use futures::future;
         use crate::db::schema::trace::traces::dsl::*;
         use diesel::prelude::*;
-        use super::Trace;
+        use crate::tracer::{RunOptions, SystemTap};
+        use super::{Trace, TraceMode};
         let conn = crate::db::connection::get_conn();
         let res = traces.load::<Trace>(&*conn).unwrap();
         for i in res {
             tokio::run(future::lazy(move || {
-                i.run(1, "STAP");
+                i.run(&SystemTap, RunOptions::new(1, TraceMode::Stack), "test");
                 Ok(())
             }))
         }
@@ -0,0 +1,10 @@
+table! {
+    api_keys (id) {
+        id -> Integer,
+        name -> Text,
+        secret -> Text,
+        scope -> Text,
+        created_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
@@ -1 +1,3 @@
-pub mod trace;
\ No newline at end of file
+pub mod key;
+pub mod run;
+pub mod trace;
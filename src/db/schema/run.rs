@@ -0,0 +1,15 @@
+table! {
+    trace_runs (id) {
+        id -> Integer,
+        trace_id -> Integer,
+        trace_type -> Text,
+        script -> Text,
+        started_by -> Text,
+        start_time -> Timestamptz,
+        end_time -> Nullable<Timestamptz>,
+        exit_status -> Nullable<Text>,
+        stderr -> Nullable<Text>,
+        chunks -> Integer,
+        bytes -> BigInt,
+    }
+}
@@ -6,5 +6,9 @@ table! {
         environment -> Array<Text>,
         values -> Array<Text>,
         options -> Array<Text>,
+        captures -> Array<Text>,
+        template_types -> Array<Text>,
+        templates -> Array<Text>,
+        kernel_probes -> Array<Text>,
     }
 }
\ No newline at end of file
@@ -1,21 +1,28 @@
-use std::process::Child;
-
-use argon2::Config;
 use chrono::{DateTime, Utc};
 use crypto_api_osrandom::OsRandom;
 use hashbrown::HashMap;
-use parking_lot::RwLock;
+use hmac::{Hmac, Mac};
+use parking_lot::{Mutex, RwLock};
+use sha2::{Digest, Sha256};
 
 use crate::config::*;
+use crate::db::model::key::{ApiKey, Scope};
+use crate::db::model::run::RunStatus;
+use crate::tracer::TracerProcess;
 
 pub struct RunningTrace {
     pub start_time: DateTime<Utc>,
     pub trace_id: i32,
-    pub child: Child
+    pub run_id: Option<i32>,
+    pub child: TracerProcess,
+    /// Why the endpoint stopped the tracer, if it did.
+    pub outcome: Option<RunStatus>,
 }
 
 impl RunningTrace {
-    pub fn kill(&mut self) {
+    /// The run is reported once the reader of its output reaps the tracer.
+    pub fn kill(&mut self, status: RunStatus) {
+        self.outcome.get_or_insert(status);
         self.child.kill().unwrap_or(());
     }
 }
@@ -30,38 +37,146 @@ pub fn put_running(x: &str, trace: RunningTrace) {
     drop(writer)
 }
 
-pub fn remove_running(x: &str) {
+pub fn remove_running(x: &str) -> Option<RunningTrace> {
     let mut writer = RUNNING.write();
-    writer.remove(x);
-    drop(writer)
+    writer.remove(x)
 }
 
-pub fn hashed_secret() -> String {
-    let mut gen = OsRandom::secure_rng();
-    let mut salt  = Vec::new();
-    salt.resize(16, 0);
-    gen.random(salt.as_mut_slice()).expect("unable to gen salt");
-    let password = global_config().secret.as_bytes();
-    let config = Config::default();
-    argon2::hash_encoded(password, salt.as_slice(), &config).expect("unable to hash secret")
+pub fn kill_running(x: &str, status: RunStatus) -> bool {
+    let mut writer = RUNNING.write();
+    writer.get_mut(x).map(|t| t.kill(status)).is_some()
 }
 
-pub fn verify(encoded: &str) -> bool {
-    match argon2::verify_encoded(encoded, global_config().secret.as_bytes()) {
-        Ok(true) => true,
-        _ => false
-    }
+pub const AUTH_SCHEME: &str = "LAMBDA-HMAC";
+
+lazy_static! {
+    // nonces of the requests accepted within the clock skew window, with their timestamps
+    static ref NONCES: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+}
+
+// requests are signed over `METHOD\nPATH\nTIMESTAMP\nNONCE\nhex(sha256(BODY))`, where the path
+// includes the query string
+fn signature(secret: &[u8], method: &str, path: &str, timestamp: i64, nonce: &str, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("hmac accepts keys of any size");
+    mac.input(format!("{}\n{}\n{}\n{}\n{}", method, path, timestamp, nonce, hex::encode(Sha256::digest(body))).as_bytes());
+    mac
+}
+
+// `who` is the credential field, `uuid=<endpoint>` or `key=<name>`
+fn sign(who: &str, secret: &[u8], method: &str, path: &str, timestamp: i64, nonce: &str, body: &[u8]) -> String {
+    let mac = signature(secret, method, path, timestamp, nonce, body);
+    format!("{} {},timestamp={},nonce={},signature={}",
+            AUTH_SCHEME, who, timestamp, nonce, hex::encode(mac.result().code()))
+}
+
+/// Authorization header of a request to `uri`.
+pub fn authorization(method: &str, uri: &str, body: &[u8]) -> String {
+    let path = uri.parse::<hyper::Uri>().ok()
+        .and_then(|x| x.path_and_query().map(|p| p.as_str().to_string()))
+        .unwrap_or_else(|| uri.to_string());
+    let mut nonce = [0_u8; 16];
+    OsRandom::secure_rng().random(&mut nonce).expect("unable to gen nonce");
+    let config = global_config();
+    sign(format!("uuid={}", config.endpoint_uuid).as_str(), config.secret.as_bytes(), method, path.as_str(),
+         Utc::now().timestamp(), hex::encode(nonce).as_str(), body)
 }
 
-pub fn authorization() -> String {
-    global_config().endpoint_uuid.clone() + hashed_secret().as_str()
+/// Who signed a request.
+enum Credential<'a> {
+    /// The platform, with the secret of the endpoint.
+    Endpoint(&'a str),
+    Key(&'a str),
 }
 
+/// The parts of a request covered by its signature.
+struct Signed<'a> {
+    method: &'a str,
+    path: &'a str,
+    body: &'a [u8],
+}
+
+// Err if the header is malformed, Ok(None) if it is not a fresh request signed with the secret
+// `secret_of` gives for its credential, otherwise the scope of that credential
+fn check<F>(header: &str, request: Signed, now: i64, skew: i64, nonces: &mut HashMap<String, i64>,
+            secret_of: F) -> Result<Option<Scope>, String>
+    where F: FnOnce(Credential) -> Option<(String, Scope)> {
+    if !header.starts_with(AUTH_SCHEME) {
+        return Err(format!("authorization scheme must be {}", AUTH_SCHEME));
+    }
+    let fields = header[AUTH_SCHEME.len()..].split(',')
+        .filter_map(|x| {
+            let mut kv = x.trim().splitn(2, '=');
+            Some((kv.next()?, kv.next()?))
+        })
+        .collect::<HashMap<_, _>>();
+    let field = |x: &str| fields.get(x).cloned().ok_or_else(|| format!("no {} in authorization header", x));
+    let timestamp = field("timestamp")?.parse::<i64>().map_err(|e| e.to_string())?;
+    let nonce = field("nonce")?;
+    let sig = hex::decode(field("signature")?).map_err(|e| e.to_string())?;
+    let credential = match (fields.get("key"), fields.get("uuid")) {
+        (Some(key), _) => Credential::Key(key),
+        (None, Some(uuid)) => Credential::Endpoint(uuid),
+        (None, None) => return Err("no key or uuid in authorization header".to_string())
+    };
+    if (now - timestamp).abs() > skew {
+        return Ok(None);
+    }
+    let (secret, scope) = match secret_of(credential) {
+        Some(x) => x,
+        None => return Ok(None)
+    };
+    if signature(secret.as_bytes(), request.method, request.path, timestamp, nonce, request.body).verify(sig.as_slice()).is_err() {
+        return Ok(None);
+    }
+    nonces.retain(|_, t| (now - *t).abs() <= skew);
+    if nonces.insert(nonce.to_string(), timestamp).is_some() {
+        return Ok(None);
+    }
+    Ok(Some(scope))
+}
+
+/// The scope granted to the request signed by `header`, each signed request is only accepted once.
+/// The platform signs with the secret of the endpoint and has every scope.
+pub fn verify(header: &str, method: &str, path: &str, body: &[u8]) -> Result<Option<Scope>, String> {
+    let config = global_config();
+    let request = Signed { method, path, body };
+    check(header, request, Utc::now().timestamp(), config.auth_skew as i64, &mut NONCES.lock(),
+          |credential| match credential {
+              Credential::Endpoint(uuid) if uuid == config.endpoint_uuid =>
+                  Some((config.secret.clone(), Scope::Admin)),
+              Credential::Endpoint(_) => None,
+              Credential::Key(name) => match ApiKey::find(name) {
+                  Ok(key) => key.and_then(|x| x.scope().map(|s| (x.secret, s))),
+                  Err(e) => {
+                      eprintln!("[ERROR] failed to look up key {}: {}", name, e);
+                      None
+                  }
+              }
+          })
+}
 
 #[test]
-fn basic_hash() {
-    let a = hashed_secret();
-    let b = argon2::verify_encoded(a.as_str(), global_config().secret.as_bytes());
-    assert!(b.unwrap());
-    assert!(verify(a.as_str()))
-}
\ No newline at end of file
+fn signed_requests() {
+    let (secret, now) = ("secret", 1_575_000_000);
+    let mut nonces = HashMap::new();
+    let header = sign("uuid=endpoint", secret.as_bytes(), "POST", "/kill", now, "abc", b"{}");
+    let mut accept = |header: &str, path: &str, body: &[u8], now: i64|
+        check(header, Signed { method: "POST", path, body }, now, 300, &mut nonces, |credential| match credential {
+            Credential::Endpoint("endpoint") => Some((secret.to_string(), Scope::Admin)),
+            Credential::Key("dashboard") => Some(("other".to_string(), Scope::Read)),
+            _ => None
+        });
+    assert_eq!(accept(header.as_str(), "/kill", b"{\"file_path\":\"x\"}", now), Ok(None));
+    assert_eq!(accept(header.as_str(), "/start_trace", b"{}", now), Ok(None));
+    assert_eq!(accept(header.as_str(), "/kill", b"{}", now + 301), Ok(None));
+    assert_eq!(accept(header.as_str(), "/kill", b"{}", now + 10), Ok(Some(Scope::Admin)));
+    // replayed
+    assert_eq!(accept(header.as_str(), "/kill", b"{}", now + 20), Ok(None));
+    let unknown = sign("uuid=other", secret.as_bytes(), "POST", "/kill", now, "def", b"{}");
+    assert_eq!(accept(unknown.as_str(), "/kill", b"{}", now), Ok(None));
+    let key = sign("key=dashboard", b"other", "POST", "/kill", now, "ghi", b"{}");
+    assert_eq!(accept(key.as_str(), "/kill", b"{}", now), Ok(Some(Scope::Read)));
+    let revoked = sign("key=revoked", b"other", "POST", "/kill", now, "jkl", b"{}");
+    assert_eq!(accept(revoked.as_str(), "/kill", b"{}", now), Ok(None));
+    assert!(accept("endpoint$argon2i$v=19", "/kill", b"{}", now).is_err());
+}
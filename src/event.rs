@@ -0,0 +1,189 @@
+use serde::*;
+
+/// Every record printed by the generated scripts starts with a header line of tab separated fields:
+/// `E <kind> <timestamp> <pid> <tid> <function> <values>`, followed by the frames of the user stack
+/// (or the lines of the histogram for latency records), one per line.
+pub const RECORD_MARK: &str = "E\t";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Call,
+    Return,
+    Latency,
+    /// Hit of a kernel tracepoint, kprobe or syscall, `function` is the name of the probe.
+    Kernel,
+}
+
+impl std::str::FromStr for EventKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "call" => Ok(EventKind::Call),
+            "return" => Ok(EventKind::Return),
+            "latency" => Ok(EventKind::Latency),
+            "kernel" => Ok(EventKind::Kernel),
+            _ => Err(format!("invalid event kind: {}", s))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceEvent {
+    pub kind: EventKind,
+    pub timestamp: u64,
+    pub pid: u32,
+    pub tid: u32,
+    pub function: String,
+    pub values: Vec<String>,
+    pub stack: Vec<String>,
+}
+
+impl TraceEvent {
+    fn from_header(line: &str) -> Option<Self> {
+        let mut fields = line[RECORD_MARK.len()..].splitn(6, '\t');
+        let kind = fields.next()?.parse().ok()?;
+        let timestamp = fields.next()?.trim().parse().ok()?;
+        let pid = fields.next()?.trim().parse().ok()?;
+        let tid = fields.next()?.trim().parse().ok()?;
+        let function = fields.next()?.to_string();
+        let values = fields.next()
+            .map(|x| x.split_whitespace().map(|x| x.to_string()).collect())
+            .unwrap_or_default();
+        Some(TraceEvent { kind, timestamp, pid, tid, function, values, stack: Vec::new() })
+    }
+}
+
+/// Incremental parser of the tracer output.
+/// A record is only complete once the next header arrives (or the output ends), so the last one is
+/// held back between calls to `feed`.
+#[derive(Default)]
+pub struct EventParser {
+    pending: Vec<u8>,
+    current: Option<TraceEvent>,
+}
+
+impl EventParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Vec<TraceEvent> {
+        self.pending.extend_from_slice(data);
+        let mut events = Vec::new();
+        while let Some(n) = self.pending.iter().position(|x| *x == b'\n') {
+            let line = self.pending.drain(..=n).collect::<Vec<u8>>();
+            let line = String::from_utf8_lossy(&line[..n]);
+            let line = line.trim_end_matches('\r');
+            if line.starts_with(RECORD_MARK) {
+                events.extend(self.current.take());
+                self.current = TraceEvent::from_header(line);
+            } else if let Some(event) = self.current.as_mut() {
+                if !line.trim().is_empty() {
+                    event.stack.push(line.trim().to_string());
+                }
+            }
+        }
+        events
+    }
+
+    pub fn finish(&mut self) -> Vec<TraceEvent> {
+        let mut events = if self.pending.is_empty() {
+            Vec::new()
+        } else {
+            self.pending.push(b'\n');
+            self.feed(&[])
+        };
+        events.extend(self.current.take());
+        events
+    }
+}
+
+/// Splits the tracer output into chunks that end on a record boundary, falling back to a line
+/// boundary for records larger than a chunk.
+pub struct Chunker {
+    pending: Vec<u8>,
+    size: usize,
+}
+
+// a line longer than this many chunks is cut anywhere
+const MAX_LINE_CHUNKS: usize = 4;
+
+fn last_record_start(data: &[u8]) -> Option<usize> {
+    (1..data.len())
+        .rev()
+        .find(|&p| data[p - 1] == b'\n' && data[p..].starts_with(RECORD_MARK.as_bytes()))
+}
+
+fn last_line_end(data: &[u8]) -> Option<usize> {
+    data.iter().rposition(|x| *x == b'\n').map(|x| x + 1)
+}
+
+impl Chunker {
+    pub fn new(size: usize) -> Self {
+        Chunker { pending: Vec::new(), size: std::cmp::max(size, 1) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Returns the chunks completed by `data`.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+        let mut chunks = Vec::new();
+        while self.pending.len() >= self.size {
+            let head = &self.pending[..self.size];
+            let cut = last_record_start(head)
+                .or_else(|| last_line_end(head))
+                .or_else(|| self.pending[self.size..].iter().position(|x| *x == b'\n').map(|x| self.size + x + 1))
+                .or(if self.pending.len() >= self.size * MAX_LINE_CHUNKS { Some(self.size) } else { None });
+            match cut {
+                Some(n) => chunks.push(self.pending.drain(..n).collect()),
+                None => break
+            }
+        }
+        chunks
+    }
+
+    /// Everything up to the last complete line, sent when the output is too slow to fill a chunk.
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        last_line_end(&self.pending).map(|n| self.pending.drain(..n).collect())
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[test]
+fn parse_events() {
+    let mut parser = EventParser::new();
+    let first = parser.feed(b"Attaching 2 probes...\nE\tcall\t100\t1\t2\tmain\targ0=1 arg1=2\n\tmain+0x1d\n\t__libc_start_main+0xe7\n\nE\tret");
+    assert!(first.is_empty());
+    let second = parser.feed(b"urn\t120\t1\t2\tmain\tretval=0\n");
+    assert_eq!(second.len(), 1);
+    assert_eq!(second[0].kind, EventKind::Call);
+    assert_eq!(second[0].values, vec!["arg0=1", "arg1=2"]);
+    assert_eq!(second[0].stack, vec!["main+0x1d", "__libc_start_main+0xe7"]);
+    let rest = parser.finish();
+    assert_eq!(rest.len(), 1);
+    assert_eq!(rest[0].kind, EventKind::Return);
+    assert_eq!(rest[0].timestamp, 120);
+    assert!(rest[0].stack.is_empty());
+}
+
+#[test]
+fn chunk_on_records() {
+    let mut chunker = Chunker::new(32);
+    assert!(chunker.feed(b"E\tcall\t1\t1\t1\tmain\t\n\tmain+0x1d\n").is_empty());
+    let chunks = chunker.feed(b"E\treturn\t2\t1\t1\tmain\t\nE\tcall");
+    assert_eq!(chunks, vec![b"E\tcall\t1\t1\t1\tmain\t\n\tmain+0x1d\n".to_vec()]);
+    assert_eq!(chunker.flush().unwrap(), b"E\treturn\t2\t1\t1\tmain\t\n".to_vec());
+    assert!(chunker.flush().is_none());
+    assert_eq!(chunker.finish(), b"E\tcall".to_vec());
+    assert!(chunker.is_empty());
+    let mut chunker = Chunker::new(4);
+    assert_eq!(chunker.feed(b"abcdef\ngh"), vec![b"abcdef\n".to_vec()]);
+    assert_eq!(chunker.feed(b"ijklmnopqrstuvw"), vec![b"ghij".to_vec()]);
+}
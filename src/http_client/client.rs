@@ -1,74 +1,250 @@
-use hyper::StatusCode;
+use std::fs::File;
+use std::io::BufReader;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Sink, Stream};
+use futures::future::Either;
+use futures::sink::Wait;
+use futures::sync::mpsc::{channel, Sender};
+use hyper::{Body, Client, Request, Response, StatusCode};
+use hyper::client::HttpConnector;
+use hyper::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE};
+use hyper_rustls::HttpsConnector;
 use serde::*;
+use tokio::prelude::FutureExt;
+use tokio::timer::Delay;
+use tokio_rustls::rustls::ClientConfig;
 
+use crate::config::{DeliveryMode, global_config};
+use crate::db::model::run::RunStatus;
 use crate::endpoint::authorization;
+use crate::event::TraceEvent;
+
+use super::encoding::*;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct SubmitInfo {
     trace: String,
     status: String,
-    body: Option<String>,
+    /// Events of the chunk, unless the platform asked for `data` instead.
+    body: Option<Vec<TraceEvent>>,
+    /// Raw tracer output of the chunk, base64 encoded, if the platform accepts `RAW`.
+    data: Option<String>,
     stderr: Option<String>,
+    /// Exit code of the tracer, in the last submission only.
+    exit_code: Option<i32>,
     no: usize
 }
 
-pub fn submit_start(x: String) {
-    let info = serde_json::to_string(
-        &SubmitInfo { trace: x, status: "start".to_string(), body: None, stderr: None, no: 0 }
-    ).unwrap();
-    let client = reqwest::Client::new();
-    let url = if cfg!(test) { "http://httpbin.org/post".to_string() } else { crate::config::global_config().platform_url.clone() + "/submit" };
-    match client
-        .post(url.as_str())
-        .header("Authorization", authorization())
-        .body(info).send() {
-        Ok(req) if req.status() == StatusCode::OK =>
-            println!("[INFO] submit successfully started"),
-        _ =>
-            eprintln!("[ERROR] server failed to response start request")
+type Connector = HttpsConnector<HttpConnector>;
+
+fn connector(ca: Option<&String>) -> Result<Connector, String> {
+    let ca = match ca {
+        Some(x) => x,
+        None => return Ok(HttpsConnector::new(4))
     };
+    // only the platform CA is trusted, the built-in roots would accept any public certificate
+    let mut tls = ClientConfig::new();
+    let mut pem = File::open(ca).map(BufReader::new).map_err(|e| format!("{}: {}", ca, e))?;
+    match tls.root_store.add_pem_file(&mut pem) {
+        Ok((valid, _)) if valid > 0 => (),
+        _ => return Err(format!("{}: no valid certificate", ca))
+    }
+    let mut http = HttpConnector::new(4);
+    http.enforce_http(false);
+    Ok(HttpsConnector::from((http, tls)))
 }
 
-pub fn submit(x: String, body: &[u8], is_end: bool, stderr: Option<String>, no: usize) {
-    let body = Some(String::from_utf8_lossy(body).to_string());
-    let info = serde_json::to_string(
-        &SubmitInfo {
-            trace: x.clone(),
-            status:
-            if is_end { "finished".to_string() } else { "WIP".to_string() },
-            body,
-            stderr,
-            no,
+lazy_static! {
+    // shared by every run so that connections to the platform are reused
+    static ref CLIENT: Client<Connector> = match connector(global_config().platform_ca.as_ref()) {
+        Ok(c) => Client::builder().build(c),
+        Err(e) => {
+            eprintln!("[ERROR] cannot load platform_ca: {}", e);
+            std::process::exit(2)
         }
-    ).unwrap();
-    let client = reqwest::Client::new();
-    let url = if cfg!(test) {
+    };
+}
+
+/// Sign and post `body` to the platform, giving up after `submit_timeout` seconds.
+pub(super) fn platform_post(url: &str, content_type: &str, encoding: Option<&str>, body: Vec<u8>)
+                            -> impl Future<Item=Response<Body>, Error=String> {
+    let mut request = Request::post(url);
+    request
+        .header(AUTHORIZATION, authorization("POST", url, body.as_slice()).as_str())
+        .header(CONTENT_TYPE, content_type);
+    if let Some(x) = encoding {
+        request.header(CONTENT_ENCODING, x);
+    }
+    match request.body(Body::from(body)) {
+        Ok(request) => Either::A(CLIENT.request(request)
+            .timeout(Duration::from_secs(global_config().submit_timeout))
+            .map_err(|e| e.to_string())),
+        Err(e) => Either::B(future::err(e.to_string()))
+    }
+}
+
+fn submit_url() -> String {
+    if cfg!(test) {
         "http://httpbin.org/post".to_string()
     } else {
-        crate::config::global_config().platform_url.clone() + "/submit"
+        global_config().platform_url.clone() + "/submit"
+    }
+}
+
+/// Send spooled submissions, several of them only if the platform accepts batches.
+/// Resolves to whether the platform accepted them.
+pub fn post_submit(mut infos: Vec<String>) -> impl Future<Item=bool, Error=()> {
+    let accepted = accepted();
+    let (content_type, body) = if infos.len() == 1 {
+        (JSON, infos.pop().unwrap())
+    } else {
+        (NDJSON, infos.join("\n"))
     };
-    match client
-        .post(url.as_str())
-        .header("Authorization", authorization())
-        .body(info).send() {
-        Ok(req) if req.status() == StatusCode::OK =>
-            println!("[INFO] submit {} of {} finished", no, x),
-        _ =>
-            eprintln!("[ERROR] server failed to response submit request")
+    let body = match accepted.encoding.encode(body.into_bytes()) {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("[ERROR] failed to encode submission: {}", e);
+            return Either::B(future::ok(false));
+        }
     };
+    let encoding = Some(accepted.encoding.name()).filter(|_| accepted.encoding != Encoding::Identity);
+    Either::A(platform_post(submit_url().as_str(), content_type, encoding, body)
+        .map(|res| {
+            update_accepted(res.headers());
+            res.status() == StatusCode::OK
+        })
+        .or_else(|e| {
+            eprintln!("[ERROR] failed to reach the platform: {}", e);
+            Ok(false)
+        }))
+}
+
+// a chunk is sent either as its events or as its raw output, as the platform prefers
+fn content(raw: &[u8], events: Vec<TraceEvent>) -> (Option<Vec<TraceEvent>>, Option<String>) {
+    if accepted().raw {
+        (None, Some(base64::encode(raw)))
+    } else {
+        (Some(events), None)
+    }
+}
+
+/// Whether the submissions of a run can be grouped into batches of at most this many bytes.
+pub fn batch_limit() -> Option<usize> {
+    if accepted().batch { Some(global_config().submit_batch_size) } else { None }
+}
+
+/// Submissions of one run.
+/// Every chunk is spooled and queued, the queue is bounded so that a slow platform holds back the
+/// reader of the tracer output instead of piling up chunks; undelivered chunks stay in the spool
+/// for the retrier.
+/// In pull mode chunks are only spooled, waiting for the platform to fetch them, and so are they
+/// while the retrier holds the run.
+pub struct Submission {
+    key: String,
+    trace: String,
+    queue: Option<Wait<Sender<usize>>>,
+}
+
+impl Submission {
+    /// Must be called within the runtime, which drives the deliveries.
+    pub fn open(key: String, trace: String) -> Self {
+        if global_config().delivery_mode == DeliveryMode::Pull {
+            return Submission { key, trace, queue: None };
+        }
+        // the retrier is delivering older chunks of the same key, it also picks up the new ones
+        if !crate::spool::claim(key.as_str()) {
+            eprintln!("[WARN] chunks of {} are already being delivered, leaving them to the retrier", key);
+            return Submission { key, trace, queue: None };
+        }
+        let (sender, receiver) = channel(global_config().submit_queue_size);
+        let (k, claimed) = (key.clone(), key.clone());
+        tokio::spawn(receiver
+            .for_each(move |no| {
+                let k = k.clone();
+                // already sent within the batch of an earlier chunk
+                if !crate::spool::is_pending(k.as_str(), no) {
+                    return Either::B(future::ok(()));
+                }
+                // give the following chunks some time to join the batch
+                let delay = if batch_limit().is_some() {
+                    Duration::from_millis(global_config().submit_batch_delay)
+                } else {
+                    Duration::from_millis(0)
+                };
+                Either::A(Delay::new(Instant::now() + delay)
+                    .then(move |_| crate::spool::deliver(k.clone()).map(move |done| if done {
+                        println!("[INFO] submit {} of {} finished", no, k)
+                    } else {
+                        eprintln!("[ERROR] server failed to response submit request of {}, will retry later", k)
+                    })))
+            })
+            .then(move |_| -> Result<(), ()> {
+                crate::spool::release(claimed.as_str());
+                Ok(())
+            }));
+        Submission { key, trace, queue: Some(sender.wait()) }
+    }
+
+    fn push(&mut self, info: SubmitInfo, raw: &[u8]) {
+        let no = info.no;
+        let info = serde_json::to_string(&info).unwrap();
+        if let Err(e) = crate::spool::store(self.key.as_str(), no, info.as_str(), raw) {
+            eprintln!("[ERROR] failed to spool chunk {} of {}: {}", no, self.key, e);
+            return;
+        }
+        if self.queue.as_mut().map(|x| x.send(no).is_err()).unwrap_or(false) {
+            eprintln!("[ERROR] submission queue of {} closed, leaving chunk {} to the retrier", self.key, no);
+        }
+    }
+
+    pub fn start(&mut self) {
+        let info = SubmitInfo { trace: self.trace.clone(), status: "start".to_string(), body: None, data: None, stderr: None, exit_code: None, no: 0 };
+        self.push(info, &[]);
+    }
+
+    pub fn chunk(&mut self, raw: &[u8], events: Vec<TraceEvent>, no: usize) {
+        let (body, data) = content(raw, events);
+        let info = SubmitInfo {
+            trace: self.trace.clone(),
+            status: "WIP".to_string(),
+            body,
+            data,
+            stderr: None,
+            exit_code: None,
+            no,
+        };
+        self.push(info, raw);
+    }
+
+    /// The last submission of a run, carrying how it ended.
+    pub fn finish(&mut self, raw: &[u8], events: Vec<TraceEvent>, status: RunStatus, exit_code: Option<i32>,
+                  stderr: Option<String>, no: usize) {
+        let (body, data) = content(raw, events);
+        let info = SubmitInfo {
+            trace: self.trace.clone(),
+            status: status.as_str().to_string(),
+            body,
+            data,
+            stderr,
+            exit_code,
+            no,
+        };
+        self.push(info, raw);
+    }
 }
 
 #[test]
 fn test_start() {
     let x = "123".to_string();
     let info = serde_json::to_string(
-        &SubmitInfo { trace: x, status: "start".to_string(), body: None, stderr: None, no: 0 }
+        &SubmitInfo { trace: x, status: "start".to_string(), body: None, data: None, stderr: None, exit_code: None, no: 0 }
     ).unwrap();
     let client = reqwest::Client::new();
     let url = "http://httpbin.org/post";
     match client
         .post(url)
-        .header("Authorization", authorization())
+        .header("Authorization", authorization("POST", url, info.as_bytes()))
         .body(info).send() {
         Ok(req) if req.status() == StatusCode::OK =>
             println!("[INFO] submit successfully started"),
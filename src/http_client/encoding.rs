@@ -0,0 +1,138 @@
+use std::io::Write;
+
+use hyper::HeaderMap;
+use hyper::header::{ACCEPT, ACCEPT_ENCODING};
+use parking_lot::RwLock;
+
+use crate::config::global_config;
+
+pub const JSON: &str = "application/json";
+/// Several submissions in one request, one JSON document per line.
+pub const NDJSON: &str = "application/x-ndjson";
+/// Listed by a platform that wants the raw tracer output of the chunks instead of their events.
+pub const RAW: &str = "application/x-lambda-raw";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "identity" => Ok(Encoding::Identity),
+            "gzip" => Ok(Encoding::Gzip),
+            "zstd" => Ok(Encoding::Zstd),
+            _ => Err(format!("unsupported encoding: {}", s))
+        }
+    }
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn encode(self, data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Identity => Ok(data),
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data.as_slice())?;
+                encoder.finish()
+            }
+            Encoding::Zstd => zstd::stream::encode_all(data.as_slice(), 0),
+        }
+    }
+}
+
+/// What the platform declared to accept in the headers of its last response.
+/// Until it answers, submissions are sent one at a time, uncompressed and carrying events.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Accepted {
+    pub encoding: Encoding,
+    pub batch: bool,
+    /// Chunks carry their raw output, base64 encoded, rather than the parsed events.
+    pub raw: bool,
+}
+
+impl Default for Accepted {
+    fn default() -> Self {
+        Accepted { encoding: Encoding::Identity, batch: false, raw: false }
+    }
+}
+
+lazy_static! {
+    static ref ACCEPTED: RwLock<Accepted> = RwLock::new(Accepted::default());
+}
+
+pub fn accepted() -> Accepted {
+    *ACCEPTED.read()
+}
+
+fn header_values(headers: &HeaderMap, name: hyper::header::HeaderName) -> Vec<String> {
+    headers.get_all(name).iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.split(';').next().unwrap().trim().to_lowercase())
+        .collect()
+}
+
+/// Pick the first of our preferred encodings that the platform lists.
+pub fn negotiate(headers: &HeaderMap, preferred: &[String]) -> Accepted {
+    let encodings = header_values(headers, ACCEPT_ENCODING);
+    let encoding = preferred.iter()
+        .filter(|x| encodings.contains(&x.to_lowercase()))
+        .filter_map(|x| x.parse().ok())
+        .next()
+        .unwrap_or(Encoding::Identity);
+    let types = header_values(headers, ACCEPT);
+    let batch = types.iter().any(|x| x == NDJSON);
+    let raw = types.iter().any(|x| x == RAW);
+    Accepted { encoding, batch, raw }
+}
+
+pub fn update_accepted(headers: &HeaderMap) {
+    let accepted = negotiate(headers, global_config().submit_encodings.as_slice());
+    let mut current = ACCEPTED.write();
+    if *current != accepted {
+        println!("[INFO] platform accepts {} submissions{} of {}", accepted.encoding.name(),
+                 if accepted.batch { " in batches" } else { "" },
+                 if accepted.raw { "raw output" } else { "events" });
+        *current = accepted;
+    }
+}
+
+#[test]
+fn negotiation() {
+    use hyper::header::HeaderValue;
+    let preferred = vec!["zstd".to_string(), "gzip".to_string()];
+    let mut headers = HeaderMap::new();
+    assert_eq!(negotiate(&headers, &preferred), Accepted::default());
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip;q=1.0, deflate"));
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json, application/x-ndjson"));
+    assert_eq!(negotiate(&headers, &preferred), Accepted { encoding: Encoding::Gzip, batch: true, raw: false });
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, zstd"));
+    assert_eq!(negotiate(&headers, &preferred).encoding, Encoding::Zstd);
+    headers.insert(ACCEPT, HeaderValue::from_static("application/x-lambda-raw"));
+    assert_eq!(negotiate(&headers, &preferred), Accepted { encoding: Encoding::Zstd, batch: false, raw: true });
+}
+
+#[test]
+fn encode_roundtrip() {
+    use std::io::Read;
+    let data = b"E\tcall\t100\t1\t2\tmain\t\n\tmain+0x1d\n".repeat(64);
+    let mut decoded = Vec::new();
+    flate2::read::GzDecoder::new(Encoding::Gzip.encode(data.clone()).unwrap().as_slice())
+        .read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, data);
+    assert_eq!(zstd::stream::decode_all(Encoding::Zstd.encode(data.clone()).unwrap().as_slice()).unwrap(), data);
+    assert_eq!(Encoding::Identity.encode(data.clone()).unwrap(), data);
+}
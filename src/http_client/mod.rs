@@ -1,3 +1,6 @@
 pub use client::*;
+pub use registry::{deregister, heartbeats};
 
 mod client;
+mod encoding;
+mod registry;
@@ -0,0 +1,117 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::{Future, future, Stream};
+use futures::future::Either;
+use hyper::StatusCode;
+use serde::*;
+use tokio::timer::Interval;
+
+use crate::config::{address, global_config};
+
+use super::client::platform_post;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Registration {
+    uuid: String,
+    /// Where the platform reaches the endpoint.
+    address: String,
+    version: String,
+    tracers: Vec<String>,
+    kernel: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Heartbeat {
+    uuid: String,
+    time: DateTime<Utc>,
+    /// Load averages over 1, 5 and 15 minutes.
+    load: Vec<f64>,
+    running: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Deregistration {
+    uuid: String,
+}
+
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+fn read_proc(path: &str) -> String {
+    std::fs::read_to_string(path).map(|x| x.trim().to_string()).unwrap_or_default()
+}
+
+fn post<T: Serialize>(path: &str, body: &T) -> impl Future<Item=bool, Error=()> {
+    let path = path.to_string();
+    let url = global_config().platform_url.clone() + path.as_str();
+    let body = serde_json::to_vec(body).unwrap();
+    platform_post(url.as_str(), "application/json", None, body)
+        .map(|res| res.status() == StatusCode::OK)
+        .or_else(move |e| {
+            eprintln!("[ERROR] failed to send {} to the platform: {}", path, e);
+            Ok(false)
+        })
+}
+
+fn register() -> impl Future<Item=bool, Error=()> {
+    let registration = Registration {
+        uuid: global_config().endpoint_uuid.clone(),
+        address: global_config().advertise_url.clone().unwrap_or_else(|| {
+            let scheme = if global_config().tls() { "https" } else { "http" };
+            format!("{}://{}", scheme, address())
+        }),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        tracers: crate::tracer::available().into_iter().map(|x| x.to_string()).collect(),
+        kernel: read_proc("/proc/sys/kernel/osrelease"),
+    };
+    post("/register", &registration).map(|ok| {
+        if ok {
+            println!("[INFO] registered to the platform");
+        }
+        REGISTERED.store(ok, Ordering::SeqCst);
+        ok
+    })
+}
+
+fn heartbeat() -> impl Future<Item=bool, Error=()> {
+    let heartbeat = Heartbeat {
+        uuid: global_config().endpoint_uuid.clone(),
+        time: Utc::now(),
+        load: read_proc("/proc/loadavg")
+            .split_whitespace()
+            .take(3)
+            .filter_map(|x| x.parse().ok())
+            .collect(),
+        running: crate::endpoint::RUNNING.read().len(),
+    };
+    post("/heartbeat", &heartbeat)
+}
+
+/// Register on the first tick and send heartbeats on the following ones, registering again
+/// whenever the platform failed to receive the registration.
+pub fn heartbeats() -> impl Future<Item=(), Error=()> {
+    Interval::new(Instant::now(), Duration::from_secs(global_config().heartbeat_interval))
+        .map_err(|e| eprintln!("[ERROR] heartbeat timer failed: {}", e))
+        .for_each(|_| {
+            let sent = if REGISTERED.load(Ordering::SeqCst) {
+                Either::A(heartbeat())
+            } else {
+                Either::B(register())
+            };
+            sent.then(|_| future::ok(()))
+        })
+}
+
+pub fn deregister() -> impl Future<Item=bool, Error=()> {
+    if !REGISTERED.load(Ordering::SeqCst) {
+        return Either::B(future::ok(false));
+    }
+    Either::A(post("/deregister", &Deregistration { uuid: global_config().endpoint_uuid.clone() })
+        .map(|ok| {
+            if ok {
+                println!("[INFO] deregistered from the platform");
+            }
+            ok
+        }))
+}
@@ -0,0 +1,106 @@
+use std::sync::Arc;
+
+use futures::{future, Future, Stream};
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{FromState, State};
+use hyper::{Body, HeaderMap, Method, StatusCode, Uri};
+
+use crate::db::model::key::Scope;
+use crate::endpoint::verify;
+
+use super::reply::ErrorReply;
+
+/// Rejects every request that is not signed with at least `scope`, except those to the public
+/// routes. The body is read here for the signature and handed back to the handler.
+#[derive(Clone)]
+pub struct AuthMiddleware {
+    public: Arc<Vec<(Method, String)>>,
+    scope: Scope,
+}
+
+impl AuthMiddleware {
+    /// `public` lists routes as `METHOD /path`, matched against the exact path of the request.
+    pub fn new(public: &[String], scope: Scope) -> Self {
+        let public = public.iter()
+            .map(|x| parse_route(x.as_str()).unwrap_or_else(|| panic!("invalid public route: {}", x)))
+            .collect();
+        AuthMiddleware { public: Arc::new(public), scope }
+    }
+
+    fn is_public(&self, method: &Method, path: &str) -> bool {
+        self.public.iter().any(|(m, p)| m == method && p == path)
+    }
+}
+
+fn parse_route(route: &str) -> Option<(Method, String)> {
+    let mut parts = route.split_whitespace();
+    let method = Method::from_bytes(parts.next()?.to_ascii_uppercase().as_bytes()).ok()?;
+    let path = parts.next().filter(|x| x.starts_with('/'))?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((method, path.to_string()))
+}
+
+fn verify_request(state: &State, body: &[u8]) -> Result<Option<Scope>, String> {
+    let headers = HeaderMap::borrow_from(state);
+    let method = Method::borrow_from(state);
+    let uri = Uri::borrow_from(state);
+    let path = uri.path_and_query().map(|x| x.as_str()).unwrap_or_else(|| uri.path());
+    headers.get("Authorization")
+        .ok_or("no authorization header".to_string())
+        .and_then(|x| x
+            .to_str()
+            .map_err(|e| e.to_string()))
+        .and_then(|x| verify(x, method.as_str(), path, body))
+}
+
+fn reject(state: State, error: String, code: StatusCode) -> Box<HandlerFuture> {
+    let body = Body::from(serde_json::to_string(&ErrorReply { error }).unwrap());
+    let res = create_response(&state, code, mime::APPLICATION_JSON, body);
+    box future::ok((state, res))
+}
+
+impl NewMiddleware for AuthMiddleware {
+    type Instance = Self;
+
+    fn new_middleware(&self) -> std::io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+impl Middleware for AuthMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Box<HandlerFuture>
+        where Chain: FnOnce(State) -> Box<HandlerFuture> + Send + 'static {
+        if self.is_public(Method::borrow_from(&state), Uri::borrow_from(&state).path()) {
+            return chain(state);
+        }
+        let body = Body::take_from(&mut state);
+        let f = body.concat2().then(move |real| match real {
+            Ok(x) => match verify_request(&state, x.as_ref()) {
+                Ok(Some(scope)) if scope >= self.scope => {
+                    state.put(Body::from(x));
+                    chain(state)
+                }
+                Ok(Some(_)) => reject(state, "forbidden".to_string(), StatusCode::FORBIDDEN),
+                Ok(None) => reject(state, "unauthorized".to_string(), StatusCode::UNAUTHORIZED),
+                Err(e) => reject(state, e, StatusCode::BAD_REQUEST)
+            },
+            Err(e) => reject(state, e.to_string(), StatusCode::BAD_REQUEST)
+        });
+        box f
+    }
+}
+
+#[test]
+fn public_routes() {
+    let auth = AuthMiddleware::new(&["GET /heartbeat".to_string(), "post /runs/1/ack".to_string()], Scope::Admin);
+    assert!(auth.is_public(&Method::GET, "/heartbeat"));
+    assert!(auth.is_public(&Method::POST, "/runs/1/ack"));
+    assert!(!auth.is_public(&Method::POST, "/heartbeat"));
+    assert!(!auth.is_public(&Method::DELETE, "/delete_trace"));
+    assert_eq!(parse_route("/heartbeat"), None);
+    assert_eq!(parse_route("GET heartbeat"), None);
+}
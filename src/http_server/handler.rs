@@ -1,76 +1,25 @@
 use std::fmt::Display;
 
 use chrono::Utc;
+use futures::future;
 use futures::prelude::*;
 use gotham::handler::HandlerFuture;
 use gotham::helpers::http::response::*;
-use gotham::state::{FromState, State};
-use hyper::{Body, HeaderMap, Response, StatusCode};
+use gotham::state::{client_addr, FromState, State};
+use hyper::{Body, Response, StatusCode};
 use rayon::prelude::*;
 use serde::Serialize;
 
 use crate::config::global_config;
+use crate::db::model::run::{RunStatus, TraceRun};
 use crate::diesel::prelude::*;
-use crate::endpoint::*;
+use crate::endpoint::kill_running;
 use crate::http_server::global_state::GlobalState;
-use crate::http_server::reply::{DeleteReply, ErrorReply, KillReply, RunningTraceReply, StartTraceReply, StateReply};
+use crate::http_server::reply::{AckReply, CheckReply, ChunksReply, DeleteReply, ErrorReply, KillReply, RunningTraceReply, ScriptReply, StartTraceReply,
+                                StateReply};
 
 use super::requests::*;
 
-fn verify_request(state: &State) -> Result<bool, String> {
-    let headers = HeaderMap::borrow_from(state);
-    headers.get("Authorization")
-        .ok_or("no authorization header".to_string())
-        .and_then(|x| x
-            .to_str()
-            .map_err(|e| e.to_string()))
-        .map(|x| x.split("$argon2").collect::<Vec<&str>>())
-        .map(|x| {
-            if x.len() == 2 {
-                let y = String::from("$argon2") + x[1];
-                x[0] == global_config().endpoint_uuid && verify(y.as_str())
-            } else { false }
-        })
-}
-
-fn with_verification(state: State, todo: Box<dyn Fn(State) -> (State, Response<Body>)>) -> (State, Response<Body>) {
-    let verification = verify_request(&state);
-    match verification {
-        Ok(true) => todo(state),
-        Ok(false) => {
-            let json = serde_json::to_string(&ErrorReply { error: "unauthorized".to_string() }).unwrap();
-            let body = Body::from(json);
-            let res = create_response(&state, StatusCode::UNAUTHORIZED, mime::APPLICATION_JSON, body);
-            (state, res)
-        }
-        Err(e) => {
-            let json = ErrorReply { error: format!("{}", e) };
-            let body = Body::from(serde_json::to_string(&json).unwrap());
-            let res = create_response(&state, StatusCode::BAD_REQUEST, mime::APPLICATION_JSON, body);
-            (state, res)
-        }
-    }
-}
-
-fn with_verification_res<E>(state: State, todo: Box<dyn Fn(State) -> Result<(State, Response<Body>), E>>) -> Result<(State, Response<Body>), E> {
-    let verification = verify_request(&state);
-    match verification {
-        Ok(true) => todo(state),
-        Ok(false) => {
-            let json = serde_json::to_string(&ErrorReply { error: "unauthorized".to_string() }).unwrap();
-            let body = Body::from(json);
-            let res = create_response(&state, StatusCode::UNAUTHORIZED, mime::APPLICATION_JSON, body);
-            Ok((state, res))
-        }
-        Err(e) => {
-            let json = ErrorReply { error: format!("{}", e) };
-            let body = Body::from(serde_json::to_string(&json).unwrap());
-            let res = create_response(&state, StatusCode::BAD_REQUEST, mime::APPLICATION_JSON, body);
-            Ok((state, res))
-        }
-    }
-}
-
 fn to_err_response<E: Display>(state: State, e: E, code: StatusCode) -> (State, Response<Body>) {
     let json = ErrorReply { error: format!("{}", e) };
     let body = Body::from(serde_json::to_string(&json).unwrap());
@@ -86,22 +35,9 @@ fn to_json_response<J: Serialize>(state: State, j: &J) -> (State, Response<Body>
 }
 
 pub fn heartbeat(state: State) -> (State, Response<Body>) {
-    let verification = verify_request(&state);
-    let temp = verification.map(|x|
-        if x {
-            let time = chrono::Utc::now();
-            let reply = super::reply::HeartbeatReply { status: "alive".to_string(), time };
-            let body = Body::from(serde_json::to_string(&reply).unwrap());
-            create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, body)
-        } else {
-            create_empty_response(&state, StatusCode::UNAUTHORIZED)
-        }
-    );
-    let response = temp.unwrap_or_else(|x| {
-        let body = Body::from(x);
-        create_response(&state, StatusCode::UNAUTHORIZED, mime::TEXT_PLAIN, body)
-    });
-    (state, response)
+    let time = chrono::Utc::now();
+    let reply = super::reply::HeartbeatReply { status: "alive".to_string(), time };
+    to_json_response(state, &reply)
 }
 
 pub fn endpoint_state(state: State) -> (State, Response<Body>) {
@@ -121,62 +57,49 @@ pub fn endpoint_state(state: State) -> (State, Response<Body>) {
 pub fn trace_list(state: State) -> (State, Response<Body>) {
     use crate::db::schema::trace::traces::dsl::*;
     use crate::db::model::trace::*;
-    with_verification(state, box |state| {
-        let conn = crate::db::connection::get_conn();
-        let result = traces
-            .load::<Trace>(&*conn).expect("failed to load trace");
-        let json = serde_json::to_string(&result).unwrap();
-        let body = Body::from(json);
-        let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, body);
-        (state, res)
-    })
+    let conn = crate::db::connection::get_conn();
+    let result = traces
+        .load::<Trace>(&*conn).expect("failed to load trace");
+    let json = serde_json::to_string(&result).unwrap();
+    let body = Body::from(json);
+    let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, body);
+    (state, res)
 }
 
 pub fn running_traces(state: State) -> (State, Response<Body>) {
-    with_verification(state, box |state| {
-        let list = {
-            let reader = crate::endpoint::RUNNING.read();
-            reader.par_iter().map(|(path, i)| {
-                RunningTraceReply::new(path.clone(), i.start_time, i.trace_id)
-            }).collect::<Vec<_>>()
-        };
-        let json = serde_json::to_string(&list).unwrap();
-        let body = Body::from(json);
-        let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, body);
-        (state, res)
-    })
+    let list = {
+        let reader = crate::endpoint::RUNNING.read();
+        reader.par_iter().map(|(path, i)| {
+            RunningTraceReply::new(path.clone(), i.start_time, i.trace_id, i.run_id)
+        }).collect::<Vec<_>>()
+    };
+    let json = serde_json::to_string(&list).unwrap();
+    let body = Body::from(json);
+    let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, body);
+    (state, res)
 }
 
 pub fn kill_trace(mut state: State) -> Box<HandlerFuture> {
     let body = Body::take_from(&mut state);
     let f = body.concat2().then(move |real| match real {
         Ok(x) => {
-            with_verification_res(state, box move |state| {
-                let json =
-                    simd_json::serde::from_slice::<KillTrace>(x.to_vec().as_mut_slice());
-                let reply = match json {
-                    Ok(e) =>
-                        {
-                            let mut writer = crate::endpoint::RUNNING.write();
-                            let res = match writer.get_mut(e.file_path.as_str()) {
-                                Some(t) => {
-                                    t.kill();
-                                    serde_json::to_string(&KillReply { killed: true }).unwrap()
-                                }
-                                None => {
-                                    serde_json::to_string(&ErrorReply { error: "no such process".to_string() }).unwrap()
-                                }
-                            };
-                            writer.remove(e.file_path.as_str());
-                            res
+            let json =
+                simd_json::serde::from_slice::<KillTrace>(x.to_vec().as_mut_slice());
+            let reply = match json {
+                Ok(e) =>
+                    {
+                        if kill_running(e.file_path.as_str(), RunStatus::Killed) {
+                            serde_json::to_string(&KillReply { killed: true }).unwrap()
+                        } else {
+                            serde_json::to_string(&ErrorReply { error: "no such process".to_string() }).unwrap()
                         }
-                    Err(e) =>
-                        serde_json::to_string(&ErrorReply { error: format!("{}", e) }).unwrap()
-                };
-                let body = Body::from(reply);
-                let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, body);
-                Ok((state, res))
-            })
+                    }
+                Err(e) =>
+                    serde_json::to_string(&ErrorReply { error: format!("{}", e) }).unwrap()
+            };
+            let body = Body::from(reply);
+            let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, body);
+            Ok((state, res))
         }
         Err(e) => {
             Ok(to_err_response(state, e, StatusCode::BAD_REQUEST))
@@ -191,31 +114,40 @@ pub fn start_trace(mut state: State) -> Box<HandlerFuture> {
     let body = Body::take_from(&mut state);
     let f = body.concat2().then(move |real| match real {
         Ok(x) => {
-            with_verification_res(state, box move |state| {
-                let json =
-                    simd_json::serde::from_slice::<StartTrace>(x.to_vec().as_mut_slice());
-                let reply = match json {
-                    Ok(e) => {
-                        let conn = crate::db::connection::get_conn();
-                        let result = traces.filter(id.eq(e.trace_id))
-                            .limit(1)
-                            .load::<Trace>(&*conn).expect("failed to load trace");
+            let json =
+                simd_json::serde::from_slice::<StartTrace>(x.to_vec().as_mut_slice());
+            let reply = match json {
+                Ok(e) => {
+                    let conn = crate::db::connection::get_conn();
+                    let result = traces.filter(id.eq(e.trace_id))
+                        .limit(1)
+                        .load::<Trace>(&*conn).expect("failed to load trace");
 
-                        if let Some(trace) = result.first() {
-                            let script = trace.run(e.lasting as _, e.trace_type.as_str());
-                            serde_json::to_string(&StartTraceReply { file_path: script })
-                        } else {
-                            serde_json::to_string(&ErrorReply { error: "no such trace".to_string() })
-                        }
-                    }
-                    Err(k) => {
-                        serde_json::to_string(&ErrorReply { error: format!("error: {}", k) })
+                    let run_options = e.options();
+                    match (result.first(), crate::tracer::backend(e.trace_type.as_str())) {
+                        (Some(trace), Some(backend)) => match crate::tracer::check(backend, trace, &run_options) {
+                            Ok(()) => {
+                                let started_by = client_addr(&state)
+                                    .map(|x| format!("http:{}", x))
+                                    .unwrap_or_else(|| "http".to_string());
+                                let (script, run_id) = trace.run(backend, run_options, started_by.as_str());
+                                serde_json::to_string(&StartTraceReply { file_path: script, run_id })
+                            }
+                            Err(k) => serde_json::to_string(&ErrorReply { error: k })
+                        },
+                        (None, _) =>
+                            serde_json::to_string(&ErrorReply { error: "no such trace".to_string() }),
+                        (_, None) =>
+                            serde_json::to_string(&ErrorReply { error: "no such trace type".to_string() })
                     }
-                };
-                let body = Body::from(reply.unwrap());
-                let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, body);
-                Ok((state, res))
-            })
+                }
+                Err(k) => {
+                    serde_json::to_string(&ErrorReply { error: format!("error: {}", k) })
+                }
+            };
+            let body = Body::from(reply.unwrap());
+            let res = create_response(&state, StatusCode::OK, mime::APPLICATION_JSON, body);
+            Ok((state, res))
         }
         Err(e) => {
             Ok(to_err_response(state, e, StatusCode::BAD_REQUEST))
@@ -230,15 +162,13 @@ pub fn put_trace(mut state: State) -> Box<HandlerFuture> {
     use crate::db::schema::trace::traces;
     use crate::db::model::trace::Trace;
     let f = body.concat2().then(|x| {
-        with_verification_res(state, box move |state| match &x {
+        match x {
             Err(e) => Ok(to_err_response(state, e, StatusCode::BAD_REQUEST)),
             Ok(body) => {
                 match simd_json::serde::from_slice::<PutTrace>(body.to_vec().as_mut_slice()) {
-                    Ok(p) => {
-                        if p.function_list.len() == 0 {
-                            Ok(to_err_response(state, "empty function list", StatusCode::BAD_REQUEST))
-                        } else if p.environment.len() != p.values.len() {
-                            Ok(to_err_response(state, "wrong size of environment values", StatusCode::BAD_REQUEST))
+                    Ok(mut p) => {
+                        if let Err(e) = p.validate().and_then(|_| p.resolve_symbols()) {
+                            Ok(to_err_response(state, e, StatusCode::BAD_REQUEST))
                         } else {
                             let conn = crate::db::connection::get_conn();
                             match diesel::insert_into(traces::table).values(&p).get_result::<Trace>(&*conn) {
@@ -253,7 +183,7 @@ pub fn put_trace(mut state: State) -> Box<HandlerFuture> {
                     Err(e) => Ok(to_err_response(state, e, StatusCode::BAD_REQUEST))
                 }
             }
-        })
+        }
     });
     box f
 }
@@ -282,4 +212,127 @@ pub fn delete_trace(mut state: State) -> Box<HandlerFuture> {
         }
     });
     box f
-}
\ No newline at end of file
+}
+
+pub fn trace_script(state: State) -> Box<HandlerFuture> {
+    use crate::db::schema::trace::traces::dsl::*;
+    use crate::db::model::trace::*;
+    let trace_id = TracePath::borrow_from(&state).id;
+    let query = ScriptQuery::borrow_from(&state);
+    let backend = match crate::tracer::backend(query.trace_type.as_str()) {
+        Some(b) => b,
+        None => return box future::ok(to_err_response(state, "no such trace type", StatusCode::BAD_REQUEST))
+    };
+    let check = query.check.unwrap_or(false);
+    let conn = crate::db::connection::get_conn();
+    let trace = match traces.filter(id.eq(trace_id)).first::<Trace>(&*conn) {
+        Ok(t) => t,
+        Err(e) => return box future::ok(to_err_response(state, e, StatusCode::NOT_FOUND))
+    };
+    let run_options = match query.options().and_then(|x| crate::tracer::check(backend, &trace, &x).map(|_| x)) {
+        Ok(o) => o,
+        Err(e) => return box future::ok(to_err_response(state, e, StatusCode::BAD_REQUEST))
+    };
+    let script = trace.to_script(backend, &run_options);
+    if !check {
+        return box future::ok(to_json_response(state, &ScriptReply { script, check: None }));
+    }
+    // compiling may take a while, it must not hold up the other requests
+    let (sender, receiver) = futures::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        sender.send(crate::tracer::compile_check(backend, &trace, &run_options)).unwrap_or(())
+    });
+    box receiver.then(move |res| match res {
+        Ok(Ok(c)) => {
+            let check = c.map(|(passed, output)| CheckReply { passed, output });
+            Ok(to_json_response(state, &ScriptReply { script, check }))
+        }
+        Ok(Err(e)) => Ok(to_err_response(state, e, StatusCode::INTERNAL_SERVER_ERROR)),
+        Err(e) => Ok(to_err_response(state, e, StatusCode::INTERNAL_SERVER_ERROR))
+    })
+}
+
+pub fn run_history(state: State) -> (State, Response<Body>) {
+    let query = RunsQuery::borrow_from(&state);
+    match TraceRun::history(query.trace_id, query.limit.unwrap_or(100)) {
+        Ok(runs) => to_json_response(state, &runs),
+        Err(e) => to_err_response(state, e, StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+pub fn run_output(state: State) -> (State, Response<Body>) {
+    let path = RunPath::borrow_from(&state);
+    match crate::spool::output(path.id.as_str()) {
+        Ok(output) => {
+            let res = create_response(&state, StatusCode::OK, mime::APPLICATION_OCTET_STREAM, output);
+            (state, res)
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound =>
+            to_err_response(state, e, StatusCode::NOT_FOUND),
+        Err(ref e) if e.kind() == std::io::ErrorKind::InvalidInput =>
+            to_err_response(state, e, StatusCode::BAD_REQUEST),
+        Err(e) => to_err_response(state, e, StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+pub fn run_stream(state: State) -> (State, Response<Body>) {
+    let path = RunPath::borrow_from(&state);
+    match crate::live::subscribe(path.id.as_str()) {
+        Some(receiver) => {
+            let events = receiver
+                .map(hyper::Chunk::from)
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe));
+            let mut res = create_response(&state, StatusCode::OK, mime::TEXT_EVENT_STREAM, Body::wrap_stream(events));
+            res.headers_mut().insert(hyper::header::CACHE_CONTROL, hyper::header::HeaderValue::from_static("no-cache"));
+            (state, res)
+        }
+        None => to_err_response(state, "run is not live", StatusCode::NOT_FOUND)
+    }
+}
+
+pub fn run_chunks(state: State) -> (State, Response<Body>) {
+    let path = RunPath::borrow_from(&state);
+    let query = ChunksQuery::borrow_from(&state);
+    match crate::spool::pending_after(path.id.as_str(), query.after, query.limit.unwrap_or(100)) {
+        Ok(pending) => {
+            let next = pending.last().map(|(no, _)| *no).or(query.after);
+            // skipping a chunk would let the platform acknowledge it without ever seeing it
+            let chunks = pending.into_iter()
+                .map(|(no, x)| serde_json::from_str(x.as_str()).map_err(|e| format!("chunk {} is corrupt: {}", no, e)))
+                .collect::<Result<Vec<_>, _>>();
+            match chunks {
+                Ok(chunks) => to_json_response(state, &ChunksReply { chunks, next }),
+                Err(e) => to_err_response(state, e, StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound =>
+            to_err_response(state, e, StatusCode::NOT_FOUND),
+        Err(ref e) if e.kind() == std::io::ErrorKind::InvalidInput =>
+            to_err_response(state, e, StatusCode::BAD_REQUEST),
+        Err(e) => to_err_response(state, e, StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+pub fn ack_chunks(mut state: State) -> Box<HandlerFuture> {
+    let body = Body::take_from(&mut state);
+    let f = body.concat2().then(move |real| match real {
+        Ok(x) => {
+            let path = RunPath::borrow_from(&state);
+            let json =
+                simd_json::serde::from_slice::<AckChunks>(x.to_vec().as_mut_slice());
+            Ok(match json {
+                Ok(e) => match crate::spool::acknowledge(path.id.as_str(), e.upto) {
+                    Ok(removed) => to_json_response(state, &AckReply { removed }),
+                    Err(ref e) if e.kind() == std::io::ErrorKind::InvalidInput =>
+                        to_err_response(state, e, StatusCode::BAD_REQUEST),
+                    Err(e) => to_err_response(state, e, StatusCode::INTERNAL_SERVER_ERROR)
+                },
+                Err(e) => to_err_response(state, e, StatusCode::BAD_REQUEST)
+            })
+        }
+        Err(e) => {
+            Ok(to_err_response(state, e, StatusCode::BAD_REQUEST))
+        }
+    });
+    Box::new(f)
+}
@@ -1,10 +1,13 @@
 pub use reply::*;
 pub use requests::*;
 pub use router::router;
+pub use tls::serve;
 
+mod auth;
 mod requests;
 mod reply;
 mod router;
 mod handler;
 mod global_state;
+mod tls;
 
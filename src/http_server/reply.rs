@@ -26,13 +26,15 @@ pub struct ErrorReply {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StartTraceReply {
-    pub file_path: String
+    pub file_path: String,
+    pub run_id: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RunningTraceReply {
     pub file_path: String,
     pub start_time: DateTime<Utc>,
+    pub run_id: Option<i32>,
     pub content: Trace,
 }
 
@@ -42,7 +44,7 @@ pub struct KillReply {
 }
 
 impl RunningTraceReply {
-    pub(crate) fn new(file_path: String, start_time: DateTime<Utc>, t_id: i32) -> Self {
+    pub(crate) fn new(file_path: String, start_time: DateTime<Utc>, t_id: i32, run_id: Option<i32>) -> Self {
         use crate::db::schema::trace::traces::dsl::*;
         use crate::db::model::trace::*;
         let conn = crate::db::connection::get_conn();
@@ -52,6 +54,7 @@ impl RunningTraceReply {
         RunningTraceReply {
             file_path,
             start_time,
+            run_id,
             content,
         }
     }
@@ -61,3 +64,27 @@ impl RunningTraceReply {
 pub struct DeleteReply {
     pub deleted: usize
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckReply {
+    pub passed: bool,
+    pub output: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScriptReply {
+    pub script: String,
+    pub check: Option<CheckReply>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunksReply {
+    pub chunks: Vec<serde_json::Value>,
+    /// Pass as `after` to get the following chunks.
+    pub next: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AckReply {
+    pub removed: usize,
+}
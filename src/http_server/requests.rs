@@ -1,6 +1,8 @@
 use serde::*;
 
+use crate::db::model::trace::{KernelProbe, parse_captures, TraceMode, validate_template};
 use crate::db::schema::trace::traces;
+use crate::tracer::{Launch, RunOptions};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RemoveTrace {
@@ -12,7 +14,26 @@ pub struct RemoveTrace {
 pub struct StartTrace {
     pub trace_type: String,
     pub trace_id: i32,
-    pub lasting: i32
+    pub lasting: i32,
+    #[serde(default)]
+    pub mode: TraceMode,
+    #[serde(default)]
+    pub pid: Option<i32>,
+    #[serde(default)]
+    pub cgroup: Option<String>,
+    #[serde(default)]
+    pub launch: Option<Launch>,
+}
+
+impl StartTrace {
+    pub fn options(&self) -> RunOptions {
+        RunOptions {
+            pid: self.pid,
+            cgroup: self.cgroup.clone(),
+            launch: self.launch.clone(),
+            ..RunOptions::new(self.lasting as _, self.mode)
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -27,10 +48,128 @@ pub struct PutTrace {
     pub function_list: Vec<String>,
     pub environment: Vec<String>,
     pub values: Vec<String>,
+    /// Passed to the tracer, runs only accept the `allowed_options` of their backend.
     pub options: Vec<String>,
+    #[serde(default)]
+    pub captures: Vec<String>,
+    /// Custom probe bodies, `templates[i]` is used when running with the `template_types[i]` backend.
+    #[serde(default)]
+    pub template_types: Vec<String>,
+    #[serde(default)]
+    pub templates: Vec<String>,
+    /// Kernel probes recorded alongside the functions, see `KernelProbe`.
+    #[serde(default)]
+    pub kernel_probes: Vec<String>,
+}
+
+impl PutTrace {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.function_list.is_empty() && self.kernel_probes.is_empty() {
+            return Err("empty function list".to_string());
+        }
+        if self.environment.len() != self.values.len() {
+            return Err("wrong size of environment values".to_string());
+        }
+        if !self.captures.is_empty() && self.captures.len() != self.function_list.len() {
+            return Err("wrong size of captures".to_string());
+        }
+        for i in &self.captures {
+            parse_captures(i.as_str())?;
+        }
+        let allowed = |x: &str| crate::tracer::names().into_iter()
+            .filter_map(crate::tracer::backend)
+            .any(|b| b.allowed_options().contains(&x));
+        if let Some(x) = self.options.iter().find(|x| !allowed(x)) {
+            return Err(format!("option not allowed: {}", x));
+        }
+        for i in &self.kernel_probes {
+            i.parse::<KernelProbe>()?;
+        }
+        if self.template_types.len() != self.templates.len() {
+            return Err("wrong size of templates".to_string());
+        }
+        if !self.templates.is_empty() && !crate::config::global_config().allow_templates {
+            return Err("templates are not allowed by this endpoint".to_string());
+        }
+        for (t, template) in self.template_types.iter().zip(self.templates.iter()) {
+            let backend = crate::tracer::backend(t.as_str())
+                .ok_or_else(|| format!("no such trace type: {}", t))?;
+            validate_template(template.as_str())?;
+            let function = self.function_list.first().map(|x| x.as_str()).unwrap_or("main");
+            backend.template_probe(self.process.as_str(), function, template.as_str(), &RunOptions::default())
+                .ok_or_else(|| format!("{} does not support templates", t))?;
+        }
+        Ok(())
+    }
+
+    /// Expand the globs of the function list and check every function against the symbol table of
+    /// the process; captures of a glob apply to all the functions it expands to.
+    pub fn resolve_symbols(&mut self) -> Result<(), String> {
+        let resolved = crate::symbols::resolve(self.process.as_str(), self.function_list.as_slice())?;
+        if !self.captures.is_empty() {
+            self.captures = resolved.iter().map(|(i, _)| self.captures[*i].clone()).collect();
+        }
+        self.function_list = resolved.into_iter().map(|(_, x)| x).collect();
+        Ok(())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeleteTrace {
     pub trace_id: i32,
 }
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+pub struct TracePath {
+    pub id: i32,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+pub struct ScriptQuery {
+    #[serde(rename = "type")]
+    pub trace_type: String,
+    pub lasting: i32,
+    pub mode: Option<String>,
+    pub pid: Option<i32>,
+    pub cgroup: Option<String>,
+    pub check: Option<bool>,
+}
+
+impl ScriptQuery {
+    pub fn options(&self) -> Result<RunOptions, String> {
+        let mode = match &self.mode {
+            Some(m) => m.parse()?,
+            None => TraceMode::default()
+        };
+        Ok(RunOptions {
+            pid: self.pid,
+            cgroup: self.cgroup.clone(),
+            ..RunOptions::new(self.lasting as _, mode)
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+pub struct RunsQuery {
+    pub trace_id: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+pub struct RunPath {
+    /// Id of the run, or the script name for runs that could not be recorded.
+    pub id: String,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+pub struct ChunksQuery {
+    /// Cursor returned by the previous fetch, chunks up to it are skipped.
+    pub after: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AckChunks {
+    /// Every chunk up to this one has been received by the platform.
+    pub upto: usize,
+}
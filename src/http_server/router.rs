@@ -1,11 +1,16 @@
 use gotham::middleware::state::StateMiddleware;
-use gotham::pipeline::single::single_pipeline;
-use gotham::pipeline::single_middleware;
+use gotham::pipeline::new_pipeline;
+use gotham::pipeline::set::{finalize_pipeline_set, new_pipeline_set};
 use gotham::router::builder::*;
 use gotham::router::Router;
 
+use crate::config::global_config;
+use crate::db::model::key::Scope;
+
+use super::auth::AuthMiddleware;
 use super::global_state::*;
 use super::handler::*;
+use super::requests::{ChunksQuery, RunPath, RunsQuery, ScriptQuery, TracePath};
 
 pub fn router() -> Router {
     // create the counter to share across handlers
@@ -14,22 +19,52 @@ pub fn router() -> Router {
     // create our state middleware to share the counter
     let middleware = StateMiddleware::new(state);
 
+    // every route requires a request signed with the scope of its pipeline, unless it is
+    // explicitly made public
+    let public = global_config().public_routes.as_slice();
+    let pipelines = new_pipeline_set();
+    let (pipelines, read) = pipelines.add(
+        new_pipeline().add(middleware.clone()).add(AuthMiddleware::new(public, Scope::Read)).build());
+    let (pipelines, run) = pipelines.add(
+        new_pipeline().add(middleware.clone()).add(AuthMiddleware::new(public, Scope::Run)).build());
+    let (pipelines, admin) = pipelines.add(
+        new_pipeline().add(middleware).add(AuthMiddleware::new(public, Scope::Admin)).build());
+    let pipelines = finalize_pipeline_set(pipelines);
 
-    // create a middleware pipeline from our middleware
-    let pipeline = single_middleware(middleware);
-
-    // construct a basic chain from our pipeline
-    let (chain, pipelines) = single_pipeline(pipeline);
-
-    // build a router with the chain & pipeline
-    build_router(chain, pipelines, |route| {
+    // build a router with the chains & pipelines, read is the default
+    build_router((read, ()), pipelines, |route| {
         route.get("/heartbeat").to(heartbeat);
         route.get("/state").to(endpoint_state);
-        route.post("/start_trace").to(start_trace);
         route.get("/list").to(trace_list);
         route.get("/running_list").to(running_traces);
-        route.post("/kill").to(kill_trace);
-        route.put("/put_trace").to(put_trace);
-        route.delete("/delete_trace").to(delete_trace);
+        route.get("/runs")
+            .with_query_string_extractor::<RunsQuery>()
+            .to(run_history);
+        route.get("/runs/:id/output")
+            .with_path_extractor::<RunPath>()
+            .to(run_output);
+        route.get("/runs/:id/stream")
+            .with_path_extractor::<RunPath>()
+            .to(run_stream);
+        route.get("/runs/:id/chunks")
+            .with_path_extractor::<RunPath>()
+            .with_query_string_extractor::<ChunksQuery>()
+            .to(run_chunks);
+        route.with_pipeline_chain((run, ()), |route| {
+            route.post("/start_trace").to(start_trace);
+            route.post("/kill").to(kill_trace);
+            // checking the script runs the tracer
+            route.get("/trace/:id/script")
+                .with_path_extractor::<TracePath>()
+                .with_query_string_extractor::<ScriptQuery>()
+                .to(trace_script);
+            route.post("/runs/:id/ack")
+                .with_path_extractor::<RunPath>()
+                .to(ack_chunks);
+        });
+        route.with_pipeline_chain((admin, ()), |route| {
+            route.put("/put_trace").to(put_trace);
+            route.delete("/delete_trace").to(delete_trace);
+        });
     })
 }
\ No newline at end of file
@@ -0,0 +1,58 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use futures::Future;
+use gotham::router::Router;
+use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+
+use crate::config::GlobalConfig;
+
+fn open(path: &str) -> Result<BufReader<File>, String> {
+    File::open(path).map(BufReader::new).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn private_key(path: &str) -> Result<PrivateKey, String> {
+    let mut keys = pkcs8_private_keys(&mut open(path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(path)?).unwrap_or_default();
+    }
+    keys.into_iter().next().ok_or_else(|| format!("{}: no private key", path))
+}
+
+/// None if HTTPS is not configured.
+fn server_config(config: &GlobalConfig) -> Result<Option<ServerConfig>, String> {
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) if config.tls_client_ca.is_some() =>
+            return Err("tls_client_ca requires tls_cert and tls_key".to_string()),
+        (None, None) => return Ok(None),
+        _ => return Err("tls_cert and tls_key must be set together".to_string())
+    };
+    let verifier = match &config.tls_client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut open(ca)?) {
+                Ok((valid, _)) if valid > 0 => AllowAnyAuthenticatedClient::new(roots),
+                _ => return Err(format!("{}: no valid certificate", ca))
+            }
+        }
+        None => NoClientAuth::new()
+    };
+    let chain = certs(&mut open(cert)?)
+        .ok().filter(|x| !x.is_empty())
+        .ok_or_else(|| format!("{}: no valid certificate", cert))?;
+    let mut server = ServerConfig::new(verifier);
+    server.set_single_cert(chain, private_key(key)?).map_err(|e| format!("{}: {}", cert, e))?;
+    Ok(Some(server))
+}
+
+/// Serve the router over HTTPS when a certificate is configured, over plain HTTP otherwise.
+pub fn serve(config: &GlobalConfig, address: &'static str, router: Router)
+             -> Result<Box<dyn Future<Item=(), Error=()> + Send>, String> {
+    // a failed handshake only ends the task of its connection
+    Ok(match server_config(config)? {
+        Some(server) => box gotham::tls::init_server(address, router, server),
+        None => box gotham::init_server(address, router)
+    })
+}
@@ -0,0 +1,134 @@
+use futures::sync::mpsc::{channel, Receiver, Sender};
+use hashbrown::HashMap;
+use parking_lot::RwLock;
+
+// messages a subscriber may lag behind before it is dropped
+const BACKLOG: usize = 256;
+
+/// Watchers of a running trace, keyed like the spool.
+struct Live {
+    status: String,
+    // output after the last newline, held back so that only whole lines are streamed
+    partial: Vec<u8>,
+    subscribers: Vec<Subscriber>,
+}
+
+struct Subscriber {
+    sender: Sender<String>,
+    // a sender may always queue one message past the backlog, this one is kept unused so that a
+    // lagging subscriber can still be told why its stream ends
+    spare: Sender<String>,
+}
+
+lazy_static! {
+    static ref LIVE: RwLock<HashMap<String, Live>> = RwLock::new(HashMap::new());
+}
+
+/// Format a server-sent event, every line of `data` becomes a `data:` field.
+pub fn event(name: &str, data: &str) -> String {
+    let mut res = format!("event: {}\n", name);
+    for line in data.split('\n') {
+        res.push_str("data: ");
+        res.push_str(line);
+        res.push('\n');
+    }
+    res.push('\n');
+    res
+}
+
+fn broadcast(live: &mut Live, message: String) {
+    // subscribers that went away or do not keep up are dropped, the latter after a last `error`
+    live.subscribers = live.subscribers.drain(..)
+        .filter_map(|mut x| match x.sender.try_send(message.clone()) {
+            Ok(()) => Some(x),
+            Err(ref e) if e.is_full() => {
+                x.spare.try_send(event("error", "lagged")).unwrap_or(());
+                None
+            }
+            Err(_) => None
+        })
+        .collect();
+}
+
+pub fn open(key: &str) {
+    LIVE.write().insert(key.to_string(), Live { status: "starting".to_string(), partial: Vec::new(), subscribers: Vec::new() });
+}
+
+/// Returns None if the run is not live.
+pub fn subscribe(key: &str) -> Option<Receiver<String>> {
+    let mut writer = LIVE.write();
+    let live = writer.get_mut(key)?;
+    let (mut sender, receiver) = channel(BACKLOG);
+    sender.try_send(event("status", live.status.as_str())).ok()?;
+    let spare = sender.clone();
+    live.subscribers.push(Subscriber { sender, spare });
+    Some(receiver)
+}
+
+pub fn publish_output(key: &str, data: &[u8]) {
+    let mut writer = LIVE.write();
+    if let Some(live) = writer.get_mut(key) {
+        live.partial.extend_from_slice(data);
+        if let Some(n) = live.partial.iter().rposition(|x| *x == b'\n') {
+            let lines = live.partial.drain(..=n).collect::<Vec<u8>>();
+            let message = event("output", String::from_utf8_lossy(&lines[..n]).as_ref());
+            broadcast(live, message);
+        }
+    }
+}
+
+pub fn publish_status(key: &str, status: &str) {
+    let mut writer = LIVE.write();
+    if let Some(live) = writer.get_mut(key) {
+        live.status = status.to_string();
+        broadcast(live, event("status", status));
+    }
+}
+
+/// Flush what is left of the output, send the final status and end the streams.
+pub fn close(key: &str, status: &str) {
+    let mut writer = LIVE.write();
+    if let Some(mut live) = writer.remove(key) {
+        if !live.partial.is_empty() {
+            let rest = std::mem::take(&mut live.partial);
+            broadcast(&mut live, event("output", String::from_utf8_lossy(&rest).as_ref()));
+        }
+        broadcast(&mut live, event("status", status));
+    }
+}
+
+#[test]
+fn stream_lines() {
+    use futures::{Future, Stream};
+    assert_eq!(event("output", "a\nb"), "event: output\ndata: a\ndata: b\n\n");
+    open("live-test");
+    let receiver = subscribe("live-test").unwrap();
+    publish_status("live-test", "running");
+    publish_output("live-test", b"E\tcall\t1\t1\t1\tma");
+    publish_output("live-test", b"in\t\n\tmain+0x1d\nE\t");
+    close("live-test", "completed");
+    assert!(subscribe("live-test").is_none());
+    let messages = receiver.collect().wait().unwrap();
+    assert_eq!(messages, vec![
+        event("status", "starting"),
+        event("status", "running"),
+        event("output", "E\tcall\t1\t1\t1\tmain\t\n\tmain+0x1d"),
+        event("output", "E\t"),
+        event("status", "completed"),
+    ]);
+}
+
+#[test]
+fn lagging_subscriber() {
+    use futures::{Future, Stream};
+    open("live-lag");
+    let receiver = subscribe("live-lag").unwrap();
+    for _ in 0..BACKLOG * 2 {
+        publish_status("live-lag", "running");
+    }
+    // the subscriber was dropped, its stream ends without the run being closed
+    let messages = receiver.collect().wait().unwrap();
+    assert!(messages.len() <= BACKLOG + 3);
+    assert_eq!(messages.last(), Some(&event("error", "lagged")));
+    close("live-lag", "completed");
+}
@@ -6,12 +6,12 @@ extern crate gotham_derive;
 #[macro_use]
 extern crate lazy_static;
 
-use std::io::{Read, Write};
-use std::process::Stdio;
+use std::io::Read;
 
-use crate::cli::{get_id, get_ids, get_stream, get_task, get_trace};
+use futures::{Future, Stream};
+
+use crate::cli::{get_check, get_id, get_ids, get_limit, get_name, get_scope, get_stream, get_task, get_trace, get_uid};
 use crate::cli::app::SUB_COMMAND;
-use crate::endpoint::hashed_secret;
 
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
@@ -20,20 +20,37 @@ mod config;
 mod cli;
 mod db;
 mod endpoint;
+mod event;
 mod http_server;
 mod http_client;
+mod live;
+mod spool;
+mod symbols;
+mod tracer;
 
 fn notice() {
-    println!("Dev Hash: {}", hashed_secret());
-    println!("Listening for requests at http://{}", config::address());
+    let scheme = if config::global_config().tls() { "https" } else { "http" };
+    println!("Listening for requests at {}://{}", scheme, config::address());
     println!("loaded config: {}", cli::config());
     println!("uuid: {}", config::global_config().endpoint_uuid);
+    println!("privilege: {:?}", config::global_config().privilege());
+    if config::global_config().root_password.is_some() {
+        eprintln!("[WARN] root_password is deprecated, use a sudoers rule, capabilities or the helper instead");
+    }
     println!("===============================================================");
 }
 
+fn shutdown() -> impl Future<Item=(), Error=()> {
+    use tokio_signal::unix::{Signal, SIGTERM};
+    let interrupt = tokio_signal::ctrl_c().flatten_stream().into_future().map(|_| ()).map_err(|_| ());
+    let terminate = Signal::new(SIGTERM).flatten_stream().into_future().map(|_| ()).map_err(|_| ());
+    interrupt.select(terminate).map(|_| ()).map_err(|_| ())
+}
+
 mod db_prelude {
     pub use diesel::prelude::*;
 
+    pub use crate::db::model::run::{RunStatus, TraceRun};
     pub use crate::db::model::trace::*;
     pub use crate::db::model::trace::Trace;
     pub use crate::db::schema::trace::traces;
@@ -44,11 +61,34 @@ fn main() {
     match SUB_COMMAND.0 {
         "endpoint" => {
             notice();
-            gotham::start(config::address(), http_server::router())
+            // in pull mode the endpoint makes no outbound calls at all
+            let push = config::global_config().delivery_mode == config::DeliveryMode::Push;
+            let mut runtime = tokio::runtime::Runtime::new().expect("unable to create runtime");
+            runtime.spawn(spool::pruner());
+            if push {
+                runtime.spawn(spool::retrier());
+                runtime.spawn(http_client::heartbeats());
+            }
+            let server = match http_server::serve(config::global_config(), config::address(), http_server::router()) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("[ERROR] {}", e);
+                    std::process::exit(1);
+                }
+            };
+            runtime.block_on(server.select2(shutdown()).then(|_| Ok::<(), ()>(()))).unwrap_or(());
+            println!("[INFO] shutting down");
+            if push {
+                runtime.block_on(http_client::deregister()).unwrap_or(false);
+            }
         }
         "add" => {
             use db_prelude::*;
-            let trace = get_trace();
+            let mut trace = get_trace();
+            if let Err(e) = trace.validate().and_then(|_| trace.resolve_symbols()) {
+                eprintln!("[ERROR] {}", e);
+                std::process::exit(1);
+            }
             let conn = crate::db::connection::get_conn();
             match diesel::insert_into(traces::table).values(&trace).get_result::<Trace>(&*conn) {
                 Ok(res) => {
@@ -116,38 +156,24 @@ fn main() {
                     eprintln!("[ERROR] {}", e)
                 }
                 Ok(res) => {
-                    let mut name = String::new();
-                    let script = if task.trace_type == "STAP" {
-                        res.to_file_stap(task.lasting as _).map(|x| {
-                            name.clone_from(&x);
-                            x
-                        })
-                    } else {
-                        res.to_file_bpf(task.lasting as _).map(|x| {
-                            name.clone_from(&x);
-                            x
-                        })
+                    let backend = match crate::tracer::backend(task.trace_type.as_str()) {
+                        Some(b) => b,
+                        None => {
+                            eprintln!("[ERROR] unknown trace type: {}", task.trace_type);
+                            std::process::exit(1);
+                        }
                     };
-                    let envs =
-                        res.environment.iter().cloned().zip(res.values.iter().cloned()).collect::<Vec<(String, String)>>();
-                    let args = res.options.clone();
-                    let flag = task.trace_type == "STAP";
-                    let res = script.and_then(|x| std::process::Command::new("sudo")
-                        .arg("-S")
-                        .arg(if flag { crate::config::global_config().stap_path.as_str() } else { crate::config::global_config().bpf_path.as_str() })
-                        .arg(x.as_str())
-                        .args(args)
-                        .stdout(Stdio::piped())
-                        .stderr(Stdio::piped())
-                        .stdin(Stdio::piped())
-                        .envs(envs)
-                        .spawn())
+                    let run_options = task.options();
+                    if let Err(e) = crate::tracer::check(backend, &res, &run_options) {
+                        eprintln!("[ERROR] {}", e);
+                        std::process::exit(1);
+                    }
+                    let script = res.to_file(backend, &run_options);
+                    let run = script.as_ref().ok()
+                        .and_then(|x| TraceRun::start(res.id, backend.name(), x.as_str(), "cli").ok())
+                        .map(|x| x.id);
+                    let res = script.and_then(|x| crate::tracer::spawn(backend, x.as_str(), &res, &run_options))
                         .and_then(move |mut child| {
-                            {
-                                let mut input = child.stdin.take().expect("unable to get input");
-                                input.write(crate::config::global_config().root_password.as_bytes()).unwrap();
-                                input.flush().unwrap();
-                            }
                             let mut output =
                                 child.stdout.take().expect("unable to get output");
                             let mut stderr =
@@ -157,13 +183,107 @@ fn main() {
                                 let res = stderr.read_to_string(&mut err);
                                 if !err.is_empty() { eprintln!("[ERROR] {}", err); }
                                 res
-                            }).map(|_| ())
+                            }).and_then(|_| child.wait())
                         });
-                    match res {
-                        Ok(()) => (),
+                    let status = match res {
+                        Ok(s) if s.success() => TraceRun::finish_some(run, RunStatus::Completed, None),
+                        Ok(s) => {
+                            eprintln!("[ERROR] tracer exited with {}", s);
+                            TraceRun::finish_some(run, RunStatus::Failed, Some(s.to_string()))
+                        }
                         Err(e) => {
-                            eprintln!("[ERROR] {}", e)
+                            eprintln!("[ERROR] {}", e);
+                            TraceRun::finish_some(run, RunStatus::Failed, Some(e.to_string()))
                         }
+                    };
+                    if let Err(e) = status {
+                        eprintln!("[ERROR] failed to record run: {}", e)
+                    }
+                }
+            }
+        }
+        "helper" => {
+            let socket = SUB_COMMAND.1.value_of("socket")
+                .unwrap_or_else(|| config::global_config().helper_socket.as_str());
+            if let Err(e) = tracer::helper::serve(socket, get_uid()) {
+                eprintln!("[ERROR] {}", e);
+                std::process::exit(1);
+            }
+        }
+        "runs" => {
+            use crate::db::model::run::TraceRun;
+            let trace = if SUB_COMMAND.1.is_present("id") { Some(get_id()) } else { None };
+            match TraceRun::history(trace, get_limit()) {
+                Ok(res) => {
+                    let json = serde_json::to_string_pretty(&res).unwrap();
+                    println!("{:#}", json);
+                }
+                Err(e) => {
+                    eprintln!("[ERROR] {}", e)
+                }
+            }
+        }
+        "create-key" => {
+            use crate::db::model::key::ApiKey;
+            match ApiKey::create(get_name(), get_scope()) {
+                Ok(key) => {
+                    println!("[INFO] new key created: {:#}", serde_json::to_string_pretty(&key).unwrap());
+                    println!("secret: {}", key.secret);
+                }
+                Err(e) => {
+                    eprintln!("[ERROR] {}", e)
+                }
+            }
+        }
+        "list-keys" => {
+            use crate::db::model::key::ApiKey;
+            match ApiKey::list() {
+                Ok(res) => {
+                    let json = serde_json::to_string_pretty(&res).unwrap();
+                    println!("{:#}", json);
+                }
+                Err(e) => {
+                    eprintln!("[ERROR] {}", e)
+                }
+            }
+        }
+        "revoke-key" => {
+            use crate::db::model::key::ApiKey;
+            match ApiKey::revoke(get_name()) {
+                Ok(0) => eprintln!("[ERROR] no such key: {}", get_name()),
+                Ok(_) => println!("[INFO] revoked key {}", get_name()),
+                Err(e) => eprintln!("[ERROR] {}", e)
+            }
+        }
+        "preview" => {
+            use db_prelude::*;
+            let conn = crate::db::connection::get_conn();
+            let task = get_task();
+            let res = match traces.filter(id.eq(task.trace_id)).first::<Trace>(&*conn) {
+                Ok(res) => res,
+                Err(e) => {
+                    eprintln!("[ERROR] {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let backend = crate::tracer::backend(task.trace_type.as_str()).unwrap();
+            let run_options = task.options();
+            if let Err(e) = crate::tracer::check(backend, &res, &run_options) {
+                eprintln!("[ERROR] {}", e);
+                std::process::exit(1);
+            }
+            println!("{}", res.to_script(backend, &run_options));
+            if get_check() {
+                match crate::tracer::compile_check(backend, &res, &run_options) {
+                    Ok(Some((true, _))) => eprintln!("[INFO] check passed"),
+                    Ok(Some((false, output))) => {
+                        eprintln!("[ERROR] check failed:\n{}", output);
+                        std::process::exit(1);
+                    }
+                    Ok(None) => eprintln!("[INFO] {} has no compile check", task.trace_type),
+                    Err(e) => {
+                        eprintln!("[ERROR] {}", e);
+                        std::process::exit(1);
                     }
                 }
             }
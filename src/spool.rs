@@ -0,0 +1,297 @@
+use std::fs::{File, read_dir};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::{future, Future, stream, Stream};
+use futures::future::{Either, Loop};
+use hashbrown::HashSet;
+use parking_lot::Mutex;
+use tokio::timer::{Delay, Interval};
+
+use crate::config::global_config;
+
+// every chunk of a run is written as `<no>.out` (raw tracer output, kept for download) and
+// `<no>.pending` (the submission, removed once the platform acknowledged it)
+const PENDING: &str = "pending";
+const OUTPUT: &str = "out";
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // runs whose chunks are being delivered by their submission queue, the retrier leaves them alone
+    static ref ACTIVE: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Name of the spool directory of a run; runs that could not be recorded use their script name.
+pub fn run_key(run_id: Option<i32>, script: &str) -> String {
+    match run_id {
+        Some(id) => id.to_string(),
+        None => Path::new(script).file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_else(|| script.to_string())
+    }
+}
+
+/// Keys are run ids, or the uuid stems of the scripts of runs that could not be recorded.
+fn is_valid_key(key: &str) -> bool {
+    (!key.is_empty() && key.chars().all(|x| x.is_ascii_digit()) && key.parse::<i32>().is_ok())
+        || uuid::Uuid::parse_str(key).is_ok()
+}
+
+fn dir(key: &str) -> PathBuf {
+    Path::new(global_config().spool_dir.as_str()).join(key)
+}
+
+// keys coming from requests must not lead outside of the spool directory
+fn checked_dir(key: &str) -> std::io::Result<PathBuf> {
+    if is_valid_key(key) {
+        Ok(dir(key))
+    } else {
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid run id: {}", key)))
+    }
+}
+
+/// Returns false if the run is already being delivered.
+pub fn claim(key: &str) -> bool {
+    ACTIVE.lock().insert(key.to_string())
+}
+
+pub fn release(key: &str) {
+    ACTIVE.lock().remove(key);
+}
+
+/// Sequence numbers of the files with the given extension, in order.
+fn chunks(dir: &Path, extension: &str) -> Vec<usize> {
+    let mut res = read_dir(dir).map(|x| x
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| x.extension().map(|x| x == extension).unwrap_or(false))
+        .filter_map(|x| x.file_stem().and_then(|x| x.to_str()).and_then(|x| x.parse().ok()))
+        .collect::<Vec<usize>>()
+    ).unwrap_or_default();
+    res.sort();
+    res
+}
+
+pub fn store(key: &str, no: usize, payload: &str, raw: &[u8]) -> std::io::Result<()> {
+    let dir = dir(key);
+    std::fs::create_dir_all(&dir)?;
+    let mut output = File::create(dir.join(format!("{}.{}", no, OUTPUT)))?;
+    output.write_all(raw)?;
+    output.flush()?;
+    // written aside and renamed, a crash must not leave a truncated submission to be delivered
+    let temp = dir.join(format!("{}.{}.tmp", no, PENDING));
+    let mut pending = File::create(&temp)?;
+    pending.write_all(payload.as_bytes())?;
+    pending.sync_all()?;
+    std::fs::rename(&temp, dir.join(format!("{}.{}", no, PENDING)))
+}
+
+pub fn is_pending(key: &str, no: usize) -> bool {
+    dir(key).join(format!("{}.{}", no, PENDING)).is_file()
+}
+
+/// Group consecutive chunks as long as their submissions fit in `limit` bytes.
+fn batches(dir: &Path, pending: Vec<usize>, limit: Option<usize>) -> Vec<Vec<usize>> {
+    let mut res: Vec<Vec<usize>> = Vec::new();
+    let mut size = 0;
+    for no in pending {
+        let len = std::fs::metadata(dir.join(format!("{}.{}", no, PENDING)))
+            .map(|x| x.len() as usize)
+            .unwrap_or(0);
+        match (limit, res.last_mut()) {
+            (Some(limit), Some(last)) if size + len <= limit => {
+                last.push(no);
+                size += len;
+            }
+            _ => {
+                res.push(vec![no]);
+                size = len;
+            }
+        }
+    }
+    res
+}
+
+/// Send the pending chunks of a run in order, stopping at the first batch the platform rejects.
+/// Resolves to whether nothing is left pending.
+pub fn deliver(key: String) -> impl Future<Item=bool, Error=()> {
+    let dir = dir(key.as_str());
+    let pending = batches(&dir, chunks(&dir, PENDING), crate::http_client::batch_limit());
+    let left = dir.clone();
+    stream::iter_ok(pending)
+        .and_then(move |batch| {
+            let paths = batch.iter()
+                .map(|no| dir.join(format!("{}.{}", no, PENDING)))
+                .collect::<Vec<_>>();
+            let payloads = paths.iter()
+                .map(|x| {
+                    let mut payload = String::new();
+                    File::open(x).and_then(|mut x| x.read_to_string(&mut payload)).map(|_| payload)
+                })
+                .collect::<std::io::Result<Vec<_>>>();
+            match payloads {
+                Ok(payloads) => Either::A(crate::http_client::post_submit(payloads).map(move |ok| {
+                    if ok {
+                        for i in paths {
+                            std::fs::remove_file(i).unwrap_or(());
+                        }
+                    }
+                    ok
+                })),
+                Err(e) => {
+                    eprintln!("[ERROR] failed to read spooled chunks {:?} of {}: {}", batch, key, e);
+                    Either::B(future::ok(false))
+                }
+            }
+        })
+        .take_while(|ok| Ok(*ok))
+        .for_each(|_| Ok(()))
+        .map(move |_| chunks(&left, PENDING).is_empty())
+}
+
+/// The raw output captured for a run so far.
+pub fn output(key: &str) -> std::io::Result<Vec<u8>> {
+    let dir = checked_dir(key)?;
+    if !dir.is_dir() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no output for this run"));
+    }
+    let mut res = Vec::new();
+    for no in chunks(&dir, OUTPUT) {
+        File::open(dir.join(format!("{}.{}", no, OUTPUT)))?.read_to_end(&mut res)?;
+    }
+    Ok(res)
+}
+
+/// Spooled submissions following the chunk `after`, at most `limit` of them.
+pub fn pending_after(key: &str, after: Option<usize>, limit: usize) -> std::io::Result<Vec<(usize, String)>> {
+    let dir = checked_dir(key)?;
+    if !dir.is_dir() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no output for this run"));
+    }
+    chunks(&dir, PENDING).into_iter()
+        .filter(|no| after.map(|x| *no > x).unwrap_or(true))
+        .take(limit)
+        .map(|no| {
+            let mut payload = String::new();
+            File::open(dir.join(format!("{}.{}", no, PENDING)))
+                .and_then(|mut x| x.read_to_string(&mut payload))
+                .map(|_| (no, payload))
+        })
+        .collect()
+}
+
+/// Drop the spooled submissions up to the chunk `upto`, returns how many were removed.
+pub fn acknowledge(key: &str, upto: usize) -> std::io::Result<usize> {
+    let dir = checked_dir(key)?;
+    let mut removed = 0;
+    for no in chunks(&dir, PENDING).into_iter().take_while(|x| *x <= upto) {
+        std::fs::remove_file(dir.join(format!("{}.{}", no, PENDING)))?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+fn undelivered() -> Vec<String> {
+    read_dir(global_config().spool_dir.as_str()).map(|x| x
+        .filter_map(|x| x.ok())
+        .filter(|x| !chunks(&x.path(), PENDING).is_empty())
+        .map(|x| x.file_name().to_string_lossy().to_string())
+        .collect()
+    ).unwrap_or_default()
+}
+
+// runs left alone for longer than the retention, with nothing pending; the directory of a run is
+// modified whenever a chunk is stored or acknowledged
+fn expired(retention: Duration) -> Vec<PathBuf> {
+    let now = SystemTime::now();
+    read_dir(global_config().spool_dir.as_str()).map(|x| x
+        .filter_map(|x| x.ok())
+        .filter(|x| !ACTIVE.lock().contains(&x.file_name().to_string_lossy().to_string()))
+        .map(|x| x.path())
+        .filter(|x| x.is_dir() && chunks(x, PENDING).is_empty())
+        .filter(|x| std::fs::metadata(x).and_then(|x| x.modified()).ok()
+            .and_then(|x| now.duration_since(x).ok())
+            .map(|x| x > retention)
+            .unwrap_or(false))
+        .collect()
+    ).unwrap_or_default()
+}
+
+/// Remove the output of the runs kept longer than `spool_retention`.
+fn prune() {
+    for dir in expired(Duration::from_secs(global_config().spool_retention)) {
+        if let Err(e) = std::fs::remove_dir_all(&dir) {
+            eprintln!("[ERROR] failed to remove expired spool {}: {}", dir.display(), e);
+        }
+    }
+}
+
+/// Drop the runs past their retention every `PRUNE_INTERVAL`, whatever the delivery mode.
+pub fn pruner() -> impl Future<Item=(), Error=()> {
+    Interval::new(Instant::now(), PRUNE_INTERVAL)
+        .map_err(|e| eprintln!("[ERROR] pruner timer failed: {}", e))
+        .for_each(|_| {
+            prune();
+            Ok(())
+        })
+}
+
+/// Keep re-sending the chunks the platform did not acknowledge, backing off while it is unreachable.
+/// Runs on the runtime of the endpoint, which drives the connections of the shared client.
+pub fn retrier() -> impl Future<Item=(), Error=()> {
+    future::loop_fn(MIN_BACKOFF, |backoff| {
+        Delay::new(Instant::now() + backoff)
+            .map_err(|e| eprintln!("[ERROR] retrier timer failed: {}", e))
+            .and_then(|_| stream::iter_ok(undelivered())
+                .filter(|x| claim(x.as_str()))
+                .and_then(|x| deliver(x.clone()).then(move |done| {
+                    release(x.as_str());
+                    Ok(!done.unwrap_or(false))
+                }))
+                .filter(|failed| *failed)
+                .fold(0, |n, _| Ok::<_, ()>(n + 1)))
+            .map(move |failed| -> Loop<(), Duration> {
+                Loop::Continue(if failed == 0 { MIN_BACKOFF } else { std::cmp::min(backoff * 2, MAX_BACKOFF) })
+            })
+    })
+}
+
+#[test]
+fn chunks_in_order() {
+    let dir = std::env::temp_dir().join(format!("spool-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for i in &[10, 2, 1] {
+        File::create(dir.join(format!("{}.{}", i, PENDING))).unwrap();
+    }
+    File::create(dir.join(format!("3.{}", OUTPUT))).unwrap();
+    assert_eq!(chunks(&dir, PENDING), vec![1, 2, 10]);
+    assert_eq!(chunks(&dir, OUTPUT), vec![3]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn run_keys() {
+    assert!(is_valid_key("42"));
+    assert!(is_valid_key("6f2b1f8e-3c4d-4e5f-8a9b-0c1d2e3f4a5b"));
+    assert!(!is_valid_key(".."));
+    assert!(!is_valid_key("../../etc"));
+    assert!(!is_valid_key("/etc/passwd"));
+    assert!(!is_valid_key("+1"));
+    assert!(!is_valid_key(""));
+}
+
+#[test]
+fn batches_by_size() {
+    let dir = std::env::temp_dir().join(format!("spool-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (i, size) in [(1, 40), (2, 40), (3, 40), (4, 100)].iter() {
+        File::create(dir.join(format!("{}.{}", i, PENDING))).unwrap().write_all(&vec![b'x'; *size]).unwrap();
+    }
+    assert_eq!(batches(&dir, chunks(&dir, PENDING), None), vec![vec![1], vec![2], vec![3], vec![4]]);
+    assert_eq!(batches(&dir, chunks(&dir, PENDING), Some(100)), vec![vec![1, 2], vec![3], vec![4]]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io::Read;
+
+use goblin::elf::Elf;
+use goblin::elf::section_header::SHN_UNDEF;
+use hashbrown::HashSet;
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    /// Name as found in the symbol table, this is what the tracers attach to.
+    pub name: String,
+    pub demangled: Option<String>,
+}
+
+impl Symbol {
+    fn new(name: &str) -> Self {
+        let demangled = rustc_demangle::try_demangle(name).ok()
+            .map(|x| format!("{:#}", x))
+            .or_else(|| cpp_demangle::Symbol::new(name).ok().map(|x| x.to_string()));
+        Symbol { name: name.to_string(), demangled }
+    }
+
+    fn matches(&self, pattern: &str) -> bool {
+        let candidates = self.demangled.iter()
+            .flat_map(|x| vec![x.as_str(), x.split('(').next().unwrap()]);
+        std::iter::once(self.name.as_str())
+            .chain(candidates)
+            .any(|x| glob_match(pattern.as_bytes(), x.as_bytes()))
+    }
+}
+
+/// Defined function symbols of the ELF file at `path`, from both `.symtab` and `.dynsym`.
+pub fn load_symbols(path: &str) -> Result<Vec<Symbol>, String> {
+    let mut buffer = Vec::new();
+    File::open(path)
+        .and_then(|mut x| x.read_to_end(&mut buffer))
+        .map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let elf = Elf::parse(buffer.as_slice()).map_err(|e| format!("cannot parse {}: {}", path, e))?;
+    let mut symbols = Vec::new();
+    let mut seen = HashSet::new();
+    let tables = vec![(&elf.syms, &elf.strtab), (&elf.dynsyms, &elf.dynstrtab)];
+    for (syms, strtab) in tables {
+        for sym in syms.iter() {
+            if !sym.is_function() || sym.st_shndx == SHN_UNDEF as usize {
+                continue;
+            }
+            if let Some(Ok(name)) = strtab.get(sym.st_name) {
+                if !name.is_empty() && seen.insert(name) {
+                    symbols.push(Symbol::new(name));
+                }
+            }
+        }
+    }
+    Ok(symbols)
+}
+
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(|x: char| x == '*' || x == '?')
+}
+
+/// Shell style matching of `*` and `?`.
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    backtrack = Some((bp, bn + 1));
+                    p = bp + 1;
+                    n = bn + 1;
+                }
+                None => return false
+            }
+        }
+    }
+    pattern[p..].iter().all(|x| *x == b'*')
+}
+
+/// Resolve every pattern of `functions` against the symbols of `process`.
+/// Returns the symbol names together with the index of the pattern they come from.
+pub fn resolve(process: &str, functions: &[String]) -> Result<Vec<(usize, String)>, String> {
+    let symbols = load_symbols(process)?;
+    let mut resolved = Vec::new();
+    let mut seen = HashSet::new();
+    for (i, pattern) in functions.iter().enumerate() {
+        let matched = if is_glob(pattern) {
+            symbols.iter().filter(|x| x.matches(pattern)).collect::<Vec<_>>()
+        } else {
+            symbols.iter().find(|x| x.name == *pattern)
+                .or_else(|| symbols.iter().find(|x| x.matches(pattern)))
+                .into_iter().collect()
+        };
+        if matched.is_empty() {
+            return Err(format!("no symbol of {} matches {}", process, pattern));
+        }
+        for j in matched {
+            if seen.insert(j.name.as_str()) {
+                resolved.push((i, j.name.clone()));
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+#[test]
+fn glob() {
+    assert!(glob_match(b"handle_*", b"handle_request"));
+    assert!(glob_match(b"*_request", b"handle_request"));
+    assert!(glob_match(b"h?ndle*st", b"handle_request"));
+    assert!(glob_match(b"*", b""));
+    assert!(!glob_match(b"handle_*", b"handler"));
+    assert!(!glob_match(b"h?ndle", b"handle_request"));
+}
+
+#[test]
+fn resolve_self() {
+    let exe = std::env::current_exe().unwrap();
+    let exe = exe.to_str().unwrap();
+    assert!(!resolve(exe, &["main".to_string()]).unwrap().is_empty());
+    assert!(resolve(exe, &["no_such_function_in_binary".to_string()]).is_err());
+}
@@ -0,0 +1,170 @@
+use crate::config::global_config;
+use crate::db::model::trace::{Capture, KernelProbe, Trace, TraceMode};
+
+use super::{capture_format, launch_command, RunOptions, TracerBackend};
+
+// every probe prints a record as described in `crate::event`
+macro_rules! template {
+    ("call") => {
+r#"
+uprobe:{0}:{1}{4} {{
+    printf("E\tcall\t%llu\t%d\t%d\t%s\t{2}\n%s\n", nsecs, pid, tid, "{1}"{3}, ustack(perf, 5));
+}}
+"#
+    };
+    ("return") => {
+r#"
+uretprobe:{0}:{1}{4} {{
+    printf("E\treturn\t%llu\t%d\t%d\t%s\t{2}\n", nsecs, pid, tid, "{1}"{3});
+}}
+"#
+    };
+    ("latency") => {
+r#"
+uprobe:{0}:{1}{3} {{
+    @entry_{2}[tid] = nsecs;
+}}
+
+uretprobe:{0}:{1} /@entry_{2}[tid]/ {{
+    @latency_{2} = hist(nsecs - @entry_{2}[tid]);
+    delete(@entry_{2}[tid]);
+}}
+"#
+    };
+    ("kernel") => {
+r#"
+{0}{1} {{
+    printf("E\tkernel\t%llu\t%d\t%d\t%s\t\n", nsecs, pid, tid, probe);
+}}
+"#
+    };
+    ("report") => {
+r#"
+    printf("E\tlatency\t%llu\t0\t0\t%s\t\n", nsecs, "{1}");
+    print(@latency_{0});
+    clear(@latency_{0});
+    clear(@entry_{0});
+"#
+    };
+}
+
+fn expr(c: &Capture) -> String {
+    match c {
+        Capture::Arg(n) => format!("arg{}", n),
+        Capture::Return => "retval".to_string(),
+    }
+}
+
+fn kernel_point(probe: &KernelProbe) -> String {
+    match probe {
+        KernelProbe::Tracepoint(category, name) => format!("tracepoint:{}:{}", category, name),
+        KernelProbe::Kprobe(function) => format!("kprobe:{}", function),
+        KernelProbe::Syscall(name) => format!("tracepoint:syscalls:sys_enter_{}", name),
+    }
+}
+
+fn predicate(run: &RunOptions) -> String {
+    predicate_with(run, Vec::new())
+}
+
+fn predicate_with(run: &RunOptions, mut conditions: Vec<String>) -> String {
+    if let Some(pid) = run.pid {
+        conditions.push(format!("pid == {}", pid));
+    }
+    if run.launch.is_some() {
+        conditions.push("pid == cpid".to_string());
+    }
+    if let Some(cgroup) = &run.cgroup {
+        conditions.push(format!("cgroup == cgroupid(\"/sys/fs/cgroup/{}\")", cgroup.trim_start_matches('/')));
+    }
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!(" /{}/", conditions.join(" && "))
+    }
+}
+
+pub struct BpfTrace;
+
+impl TracerBackend for BpfTrace {
+    fn name(&self) -> &'static str {
+        "BPF"
+    }
+
+    fn extension(&self) -> &'static str {
+        "bpf"
+    }
+
+    fn binary_path(&self) -> &str {
+        global_config().bpf_path.as_str()
+    }
+
+    fn allowed_options(&self) -> &'static [&'static str] {
+        &["-v", "-k", "-kk"]
+    }
+
+    fn arguments(&self, script: &str, options: &[String], run: &RunOptions) -> Vec<String> {
+        let mut args = Vec::new();
+        if run.launch.is_some() {
+            args.push("-c".to_string());
+            args.push(launch_command(script));
+        }
+        args.push(script.to_string());
+        args.extend(options.iter().cloned());
+        args
+    }
+
+    fn probe(&self, trace: &Trace, index: usize, function: &str, run: &RunOptions) -> String {
+        let predicate = predicate(run);
+        if run.mode == TraceMode::Latency {
+            return format!(template!("latency"), trace.process, function, index, predicate);
+        }
+        let captures = trace.captures_of(index);
+        let args = captures.iter().filter(|c| **c != Capture::Return).collect::<Vec<_>>();
+        let (fmt, vals) = capture_format(&args, expr);
+        let mut probe = format!(template!("call"), trace.process, function, fmt, vals, predicate);
+        if captures.contains(&Capture::Return) {
+            let (fmt, vals) = capture_format(&[&Capture::Return], expr);
+            probe.push_str(format!(template!("return"), trace.process, function, fmt, vals, predicate).as_str());
+        }
+        probe
+    }
+
+    fn kernel_probe(&self, trace: &Trace, _index: usize, probe: &KernelProbe, run: &RunOptions) -> Option<String> {
+        // kernel probes fire for every process, fall back to the name of the traced one
+        let conditions = if run.pid.is_some() || run.launch.is_some() {
+            Vec::new()
+        } else {
+            vec![format!("comm == \"{}\"", trace.comm())]
+        };
+        Some(format!(template!("kernel"), kernel_point(probe), predicate_with(run, conditions)))
+    }
+
+    fn template_probe(&self, process: &str, function: &str, body: &str, run: &RunOptions) -> Option<String> {
+        Some(format!("\nuprobe:{}:{}{} {{\n{}\n}}\n", process, function, predicate(run), body))
+    }
+
+    fn epilogue(&self, trace: &Trace, run: &RunOptions) -> String {
+        let mut content = String::new();
+        if run.mode == TraceMode::Latency {
+            // bpftrace dumps every remaining map on exit, report and clear them as records instead
+            content.push_str("\nEND {");
+            for (i, x) in trace.function_list.iter().enumerate() {
+                content.push_str(format!(template!("report"), i, x).as_str());
+            }
+            content.push_str("}\n");
+        }
+        content
+    }
+
+    fn termination(&self, run: &RunOptions) -> String {
+        if run.launch.is_some() {
+            return String::new();
+        }
+        format!("interval:s:{} {{ exit(); }}\n", run.duration)
+    }
+
+    fn check_arguments(&self, script: &str, _run: &RunOptions) -> Option<Vec<String>> {
+        Some(vec!["-d".to_string(), script.to_string()])
+    }
+}
@@ -0,0 +1,275 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+
+use parking_lot::Mutex;
+use serde::*;
+
+use crate::config::global_config;
+use crate::db::connection::get_conn;
+use crate::db::model::trace::{Trace, TraceMode};
+use crate::diesel::prelude::*;
+
+use super::{backend, PrivilegeMode, RunOptions, TracerBackend};
+use super::process::{ChannelReader, TracerProcess};
+
+// every reply of the helper is a frame: a tag byte, the length of the payload as a big endian u32,
+// then the payload
+const STDOUT: u8 = 1;
+const STDERR: u8 = 2;
+/// Payload is the raw wait status as a big endian i32.
+const EXIT: u8 = 3;
+/// Payload is the reason the request was refused or failed.
+const ERROR: u8 = 4;
+
+/// The only operations the helper performs. The helper loads the trace and generates its script
+/// itself, from names checked by `tracer::check`; custom templates are refused.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Request {
+    Run {
+        backend: String,
+        trace: i32,
+        run: RunOptions,
+    },
+    Check {
+        backend: String,
+        trace: i32,
+        run: RunOptions,
+    },
+    /// Stop the tracer of the connection.
+    Kill,
+}
+
+fn write_frame<W: Write>(w: &mut W, tag: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 5);
+    frame.push(tag);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    w.write_all(frame.as_slice())?;
+    w.flush()
+}
+
+fn read_frame<R: Read>(r: &mut R) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0_u8; 5];
+    match r.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e)
+    }
+    let mut len = [0_u8; 4];
+    len.copy_from_slice(&header[1..]);
+    let mut payload = vec![0_u8; u32::from_be_bytes(len) as usize];
+    r.read_exact(payload.as_mut_slice())?;
+    Ok(Some((header[0], payload)))
+}
+
+fn raw_status(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => (code & 0xff) << 8,
+        (None, Some(signal)) => signal,
+        (None, None) => 0xff << 8,
+    }
+}
+
+/// Send `request` to the helper, the tracer it starts is read and stopped through the connection.
+pub fn request(request: &Request) -> std::io::Result<TracerProcess> {
+    let mut socket = UnixStream::connect(global_config().helper_socket.as_str())?;
+    socket.write_all(serde_json::to_string(request)?.as_bytes())?;
+    socket.write_all(b"\n")?;
+    let (stdout, stdout_receiver) = channel();
+    let (stderr, stderr_receiver) = channel();
+    let (exit, exit_receiver) = channel();
+    let mut reader = socket.try_clone()?;
+    std::thread::spawn(move || loop {
+        let res = match read_frame(&mut reader) {
+            Ok(Some((STDOUT, data))) => {
+                stdout.send(data).unwrap_or(());
+                continue;
+            }
+            Ok(Some((STDERR, data))) => {
+                stderr.send(data).unwrap_or(());
+                continue;
+            }
+            Ok(Some((EXIT, data))) if data.len() == 4 => {
+                let mut raw = [0_u8; 4];
+                raw.copy_from_slice(data.as_slice());
+                Ok(ExitStatus::from_raw(i32::from_be_bytes(raw)))
+            }
+            Ok(Some((ERROR, data))) =>
+                Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, String::from_utf8_lossy(&data).to_string())),
+            Ok(Some(_)) =>
+                Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unexpected reply of the helper")),
+            Ok(None) =>
+                Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "helper closed the connection")),
+            Err(e) => Err(e)
+        };
+        exit.send(res).unwrap_or(());
+        break;
+    });
+    Ok(TracerProcess::helper(box ChannelReader::new(stdout_receiver), box ChannelReader::new(stderr_receiver),
+                             socket, exit_receiver))
+}
+
+fn peer_cred(stream: &UnixStream) -> std::io::Result<(u32, u32)> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if res == 0 { Ok((cred.uid, cred.gid)) } else { Err(std::io::Error::last_os_error()) }
+}
+
+fn remove_script(script: &str) {
+    std::fs::remove_file(script).unwrap_or(());
+    std::fs::remove_file(format!("{}.launch", script)).unwrap_or(());
+}
+
+fn load_trace(trace_id: i32) -> Result<Trace, String> {
+    use crate::db::schema::trace::traces::dsl::*;
+    traces.filter(id.eq(trace_id))
+        .first::<Trace>(&*get_conn())
+        .map_err(|e| format!("trace {}: {}", trace_id, e))
+}
+
+// the script the helper generates for the request, with the command line running it
+fn command(request: Request, user: (u32, u32)) -> Result<(&'static dyn TracerBackend, String, Vec<String>), String> {
+    let (check, name, trace_id, mut run) = match request {
+        Request::Run { backend, trace, run } => (false, backend, trace, run),
+        Request::Check { backend, trace, run } => (true, backend, trace, run),
+        Request::Kill => return Err("no tracer to kill".to_string())
+    };
+    let b = backend(name.as_str()).ok_or_else(|| format!("unknown trace type: {}", name))?;
+    let trace = load_trace(trace_id)?;
+    super::check(b, &trace, &run)?;
+    // a template is tracer code, there is nothing in it to check
+    if run.mode == TraceMode::Stack && trace.template_of(b.name()).is_some() {
+        return Err("custom templates are not run by the helper".to_string());
+    }
+    // the launched process keeps the privileges of the endpoint
+    if let Some(launch) = run.launch.as_mut() {
+        launch.user = Some(user);
+    }
+    let script = trace.to_file(b, &run).map_err(|e| e.to_string())?;
+    let args = if check {
+        b.check_arguments(script.as_str(), &run).ok_or_else(|| format!("{} has no compile check", name))
+    } else {
+        Ok(b.arguments(script.as_str(), trace.options.as_slice(), &run))
+    };
+    match args {
+        Ok(args) => Ok((b, script, args)),
+        Err(e) => {
+            remove_script(script.as_str());
+            Err(e)
+        }
+    }
+}
+
+fn pump<R: Read>(mut from: R, to: Arc<Mutex<UnixStream>>, tag: u8) {
+    let mut buffer = vec![0_u8; 4096];
+    loop {
+        match from.read(buffer.as_mut_slice()) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => if write_frame(&mut *to.lock(), tag, &buffer[..n]).is_err() { break }
+        }
+    }
+}
+
+fn handle(stream: UnixStream, allowed: u32) -> std::io::Result<()> {
+    let user = peer_cred(&stream)?;
+    let uid = user.0;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    if uid != allowed && uid != 0 {
+        return write_frame(&mut *writer.lock(), ERROR, b"not allowed to use the helper");
+    }
+    let mut lines = BufReader::new(stream).lines();
+    let request = match lines.next() {
+        Some(line) => serde_json::from_str::<Request>(line?.as_str()),
+        None => return Ok(())
+    };
+    let (b, script, args) = match request.map_err(|e| e.to_string()).and_then(|x| command(x, user)) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("[ERROR] refused request of uid {}: {}", uid, e);
+            return write_frame(&mut *writer.lock(), ERROR, e.as_bytes());
+        }
+    };
+    // the environment of the trace only reaches a launched process, through its launcher
+    let mut child = match super::local(PrivilegeMode::Capabilities, b.binary_path(), args, Vec::new()) {
+        Ok(x) => x,
+        Err(e) => {
+            remove_script(script.as_str());
+            return write_frame(&mut *writer.lock(), ERROR, e.to_string().as_bytes());
+        }
+    };
+    let stdout = child.stdout.take().expect("unable to get output");
+    let stderr = child.stderr.take().expect("unable to get output");
+    let child = Arc::new(Mutex::new(child));
+    let finished = Arc::new(AtomicBool::new(false));
+    // the tracer is stopped on request, and also when the endpoint goes away
+    let (killer, reaped) = (child.clone(), finished.clone());
+    std::thread::spawn(move || {
+        for line in lines {
+            match line.ok().and_then(|x| serde_json::from_str::<Request>(x.as_str()).ok()) {
+                Some(Request::Kill) => break,
+                _ => continue
+            }
+        }
+        if !reaped.load(Ordering::SeqCst) {
+            killer.lock().kill().unwrap_or(());
+        }
+    });
+    let err_writer = writer.clone();
+    let errors = std::thread::spawn(move || pump(stderr, err_writer, STDERR));
+    pump(stdout, writer.clone(), STDOUT);
+    errors.join().unwrap_or(());
+    let status = child.lock().wait();
+    finished.store(true, Ordering::SeqCst);
+    remove_script(script.as_str());
+    let status = status?;
+    let mut w = writer.lock();
+    write_frame(&mut *w, EXIT, &raw_status(status).to_be_bytes())
+}
+
+/// Serve the requests of the endpoint running as `uid`, meant to be run as root.
+pub fn serve(path: &str, uid: u32) -> std::io::Result<()> {
+    if Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // anyone may connect, the peer credentials decide who is served
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o666))?;
+    println!("[INFO] helper listening at {} for uid {}", path, uid);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(s) => {
+                std::thread::spawn(move || if let Err(e) = handle(s, uid) {
+                    eprintln!("[ERROR] helper connection failed: {}", e);
+                });
+            }
+            Err(e) => eprintln!("[ERROR] helper failed to accept a connection: {}", e)
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn frames() {
+    let mut buffer = Vec::new();
+    write_frame(&mut buffer, STDOUT, b"E\tcall\n").unwrap();
+    write_frame(&mut buffer, EXIT, &raw_status(ExitStatus::from_raw(2 << 8)).to_be_bytes()).unwrap();
+    let mut reader = buffer.as_slice();
+    assert_eq!(read_frame(&mut reader).unwrap(), Some((STDOUT, b"E\tcall\n".to_vec())));
+    let (tag, payload) = read_frame(&mut reader).unwrap().unwrap();
+    assert_eq!(tag, EXIT);
+    assert_eq!(payload, (2_i32 << 8).to_be_bytes().to_vec());
+    assert_eq!(read_frame(&mut reader).unwrap(), None);
+}
@@ -0,0 +1,374 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use hashbrown::HashMap;
+use serde::*;
+
+use crate::config::{global_config, PrivilegeMode};
+use crate::db::model::trace::{Capture, KernelProbe, Trace, TraceMode};
+
+pub use self::bpf::BpfTrace;
+pub use self::perf::Perf;
+pub use self::process::TracerProcess;
+pub use self::stap::SystemTap;
+
+mod bpf;
+pub mod helper;
+mod perf;
+mod process;
+mod stap;
+
+/// Start the traced process under the tracer instead of attaching to every process using it.
+/// The environment of the trace is given to the process rather than to the tracer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Launch {
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    /// Uid and gid the process is started as when the tracer runs as root, those of the endpoint
+    /// unless the helper sets them to the ones of its peer.
+    #[serde(skip)]
+    pub user: Option<(u32, u32)>,
+}
+
+impl Launch {
+    /// Shell script starting the traced process, the tracer runs it with `/bin/sh <script>.launch`.
+    fn script(&self, trace: &Trace) -> String {
+        let mut script = String::new();
+        if let Some(dir) = &self.working_dir {
+            script.push_str(format!("cd {} || exit 1\n", quote(dir)).as_str());
+        }
+        // without privileges there is nothing to drop, and setpriv could not clear the groups
+        let (uid, gid) = self.user.unwrap_or_else(|| unsafe { (libc::getuid(), libc::getgid()) });
+        script.push_str("drop=\n");
+        script.push_str(format!("[ \"$(id -u)\" -eq 0 ] && drop=\"setpriv --reuid={} --regid={} --clear-groups\"\n", uid, gid).as_str());
+        script.push_str("exec $drop env");
+        for (k, v) in trace.environment.iter().zip(trace.values.iter()) {
+            script.push(' ');
+            script.push_str(quote(format!("{}={}", k, v).as_str()).as_str());
+        }
+        script.push(' ');
+        script.push_str(quote(trace.process.as_str()).as_str());
+        for i in &self.args {
+            script.push(' ');
+            script.push_str(quote(i).as_str());
+        }
+        // the output of the process is discarded, it would mix with the records printed by the
+        // tracer, and nothing drains the stderr of the tracer before it exits
+        script.push_str(" >/dev/null 2>&1\n");
+        script
+    }
+}
+
+/// Options of a single run of a trace.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunOptions {
+    /// Ignored when launching, the run then lasts as long as the launched process.
+    pub duration: usize,
+    pub mode: TraceMode,
+    /// Only trace the process with this pid.
+    pub pid: Option<i32>,
+    /// Only trace the processes in this cgroup, relative to `/sys/fs/cgroup`.
+    pub cgroup: Option<String>,
+    pub launch: Option<Launch>,
+}
+
+impl RunOptions {
+    pub fn new(duration: usize, mode: TraceMode) -> Self {
+        RunOptions { duration, mode, ..Default::default() }
+    }
+}
+
+pub fn launch_command(script: &str) -> String {
+    format!("/bin/sh {}.launch", script)
+}
+
+pub fn write_launcher(script: &str, trace: &Trace, run: &RunOptions) -> std::io::Result<()> {
+    if let Some(launch) = &run.launch {
+        let mut file = File::create(format!("{}.launch", script))?;
+        file.write_all(launch.script(trace).as_bytes())?;
+        file.flush()?;
+    }
+    Ok(())
+}
+
+// cgroups end up in the generated scripts, only plain relative paths are accepted
+fn is_valid_cgroup(cgroup: &str) -> bool {
+    let path = cgroup.trim_start_matches('/');
+    !path.is_empty()
+        && !path.starts_with('-')
+        && path.chars().all(|x| x.is_ascii_alphanumeric() || "._/-".contains(x))
+        && path.split('/').all(|x| x != "..")
+}
+
+// symbol names end up unquoted in bpftrace probes and quoted in stap ones, only what the symbol
+// tables hold is accepted
+fn is_valid_symbol(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|x| x.is_ascii_alphanumeric() || "_.$@".contains(x))
+}
+
+fn is_valid_process(path: &str) -> bool {
+    !path.is_empty()
+        && !path.starts_with('-')
+        && path.chars().all(|x| x.is_ascii_alphanumeric() || "_./+-".contains(x))
+}
+
+/// Checks shared by all backends, followed by the ones of `backend`.
+pub fn check(backend: &dyn TracerBackend, trace: &Trace, run: &RunOptions) -> Result<(), String> {
+    if run.launch.is_some() && run.pid.is_some() {
+        return Err("cannot both launch a process and attach to a pid".to_string());
+    }
+    if !is_valid_process(trace.process.as_str()) {
+        return Err(format!("invalid process: {}", trace.process));
+    }
+    if let Some(x) = trace.function_list.iter().find(|x| !is_valid_symbol(x)) {
+        return Err(format!("invalid function name: {}", x));
+    }
+    // the trace may have been stored before templates were disallowed
+    if run.mode == TraceMode::Stack && trace.template_of(backend.name()).is_some()
+        && !global_config().allow_templates {
+        return Err("templates are not allowed by this endpoint".to_string());
+    }
+    // kernel probes would drown the histograms of the latency mode
+    if run.mode == TraceMode::Latency && !trace.kernel_probes.is_empty() {
+        return Err("kernel probes are only supported in stack mode".to_string());
+    }
+    if let Some(cgroup) = run.cgroup.as_ref().filter(|x| !is_valid_cgroup(x)) {
+        return Err(format!("invalid cgroup: {}", cgroup));
+    }
+    if let Some(x) = trace.options.iter().find(|x| !backend.allowed_options().contains(&x.as_str())) {
+        return Err(format!("option not allowed by {}: {}", backend.name(), x));
+    }
+    backend.check(trace, run)
+}
+
+/// A tool able to run the probes of a `Trace`.
+/// Registered backends are selected by the `trace_type` of a request.
+pub trait TracerBackend: Send + Sync {
+    /// The `trace_type` selecting this backend.
+    fn name(&self) -> &'static str;
+    fn extension(&self) -> &'static str;
+    fn binary_path(&self) -> &str;
+    /// The tracing tool the backend depends on, used to tell whether it is installed.
+    fn tool_path(&self) -> &str {
+        self.binary_path()
+    }
+    /// Options a trace may pass to the tracer. They only change what the tracer reports, anything
+    /// else could change what runs with its privileges.
+    fn allowed_options(&self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Arguments passed to the binary to run the generated script.
+    fn arguments(&self, script: &str, options: &[String], _run: &RunOptions) -> Vec<String> {
+        let mut args = vec![script.to_string()];
+        args.extend(options.iter().cloned());
+        args
+    }
+    /// Reject the options this backend cannot honour.
+    fn check(&self, _trace: &Trace, _run: &RunOptions) -> Result<(), String> {
+        Ok(())
+    }
+    /// Content put before all probes.
+    fn prologue(&self, _trace: &Trace, _run: &RunOptions) -> String {
+        String::new()
+    }
+    /// Probes of the function at `index` of the function list.
+    fn probe(&self, trace: &Trace, index: usize, function: &str, run: &RunOptions) -> String;
+    /// Probe recording the hits of a kernel probe, `None` if the backend cannot attach to it.
+    fn kernel_probe(&self, _trace: &Trace, _index: usize, _probe: &KernelProbe, _run: &RunOptions) -> Option<String> {
+        None
+    }
+    /// Probe running a custom `body` when `function` is hit, `None` if the backend has no probe bodies.
+    fn template_probe(&self, _process: &str, _function: &str, _body: &str, _run: &RunOptions) -> Option<String> {
+        None
+    }
+    /// Content appended after all probes.
+    fn epilogue(&self, _trace: &Trace, _run: &RunOptions) -> String {
+        String::new()
+    }
+    /// Clause stopping the trace after `run.duration` seconds.
+    fn termination(&self, run: &RunOptions) -> String;
+    /// Arguments compiling the script without attaching any probe, if the backend can do so.
+    fn check_arguments(&self, _script: &str, _run: &RunOptions) -> Option<Vec<String>> {
+        None
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: HashMap<&'static str, Box<dyn TracerBackend>> = {
+        let mut registry = HashMap::new();
+        register(&mut registry, box SystemTap);
+        register(&mut registry, box BpfTrace);
+        register(&mut registry, box Perf);
+        registry
+    };
+}
+
+fn register(registry: &mut HashMap<&'static str, Box<dyn TracerBackend>>, backend: Box<dyn TracerBackend>) {
+    registry.insert(backend.name(), backend);
+}
+
+pub fn backend(name: &str) -> Option<&'static dyn TracerBackend> {
+    REGISTRY.get(name).map(|x| x.as_ref())
+}
+
+pub fn names() -> Vec<&'static str> {
+    let mut names = REGISTRY.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+fn installed(tool: &str) -> bool {
+    if tool.contains('/') {
+        return Path::new(tool).is_file();
+    }
+    std::env::var_os("PATH")
+        .map(|x| std::env::split_paths(&x).any(|x| x.join(tool).is_file()))
+        .unwrap_or(false)
+}
+
+/// Backends whose tracing tool is installed on this host.
+pub fn available() -> Vec<&'static str> {
+    names().into_iter()
+        .filter(|x| backend(x).map(|b| installed(b.tool_path())).unwrap_or(false))
+        .collect()
+}
+
+// runs `binary` with the privileges granted by `mode`, the helper is handled by the callers as it
+// builds the command line itself
+fn local(mode: PrivilegeMode, binary: &str, args: Vec<String>, envs: Vec<(String, String)>) -> std::io::Result<TracerProcess> {
+    let mut command = match mode {
+        PrivilegeMode::SudoPassword => {
+            let mut c = Command::new("sudo");
+            c.arg("-S").arg(binary);
+            c
+        }
+        PrivilegeMode::Sudo => {
+            let mut c = Command::new("sudo");
+            c.arg("-n").arg(binary);
+            c
+        }
+        PrivilegeMode::Capabilities | PrivilegeMode::Helper => Command::new(binary),
+    };
+    let stdin = if mode == PrivilegeMode::SudoPassword { Stdio::piped() } else { Stdio::null() };
+    let mut child = command
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .stdin(stdin)
+        .envs(envs)
+        .spawn()?;
+    if mode == PrivilegeMode::SudoPassword {
+        let password = global_config().root_password.as_ref().ok_or_else(||
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "root_password is not set"))?;
+        let mut input = child.stdin.take().expect("unable to get input");
+        input.write_all(password.as_bytes())?;
+        input.write_all(b"\n")?;
+        input.flush()?;
+    }
+    Ok(TracerProcess::local(child))
+}
+
+pub fn spawn(backend: &dyn TracerBackend, script: &str, trace: &Trace, run: &RunOptions) -> std::io::Result<TracerProcess> {
+    // a launched process gets the environment through its launcher instead
+    let envs = if run.launch.is_some() {
+        Vec::new()
+    } else {
+        trace.environment.iter().cloned().zip(trace.values.iter().cloned()).collect::<Vec<(String, String)>>()
+    };
+    match global_config().privilege() {
+        PrivilegeMode::Helper => helper::request(&helper::Request::Run {
+            backend: backend.name().to_string(),
+            trace: trace.id,
+            run: run.clone(),
+        }),
+        mode => local(mode, backend.binary_path(), backend.arguments(script, trace.options.as_slice(), run), envs)
+    }
+}
+
+/// Compile the script of `trace` without running it.
+/// Returns `None` if the backend has no such check, otherwise whether it passed and what the tracer printed.
+pub fn compile_check(backend: &dyn TracerBackend, trace: &Trace, run: &RunOptions) -> std::io::Result<Option<(bool, String)>> {
+    let script = trace.to_file(backend, run)?;
+    let result = match backend.check_arguments(script.as_str(), run) {
+        None => Ok(None),
+        Some(args) => {
+            let checker = match global_config().privilege() {
+                PrivilegeMode::Helper => helper::request(&helper::Request::Check {
+                    backend: backend.name().to_string(),
+                    trace: trace.id,
+                    run: run.clone(),
+                }),
+                mode => local(mode, backend.binary_path(), args, Vec::new())
+            };
+            checker.and_then(|x| x.wait_with_output()).map(|x| {
+                let mut output = String::from_utf8_lossy(x.stderr.as_slice()).to_string();
+                output.push_str(String::from_utf8_lossy(x.stdout.as_slice()).as_ref());
+                Some((x.status.success(), output))
+            })
+        }
+    };
+    std::fs::remove_file(script.as_str()).unwrap_or(());
+    std::fs::remove_file(format!("{}.launch", script)).unwrap_or(());
+    result
+}
+
+/// Quote `s` as a single word for `sh`.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Render the captures as the values field of a record and the matching printf arguments.
+fn capture_format<F: Fn(&Capture) -> String>(captures: &[&Capture], expr: F) -> (String, String) {
+    let mut fmt = String::new();
+    let mut args = String::new();
+    for c in captures {
+        if !fmt.is_empty() {
+            fmt.push(' ');
+        }
+        match c {
+            Capture::Arg(n) => fmt.push_str(format!("arg{}=%d", n).as_str()),
+            Capture::Return => fmt.push_str("retval=%d"),
+        }
+        args.push_str(", ");
+        args.push_str(expr(c).as_str());
+    }
+    (fmt, args)
+}
+
+#[test]
+fn quote_words() {
+    assert_eq!(quote("main"), "'main'");
+    assert_eq!(quote("it's"), r"'it'\''s'");
+}
+
+#[test]
+fn allowed_options() {
+    assert!(backend("BPF").unwrap().allowed_options().contains(&"-v"));
+    assert!(!backend("STAP").unwrap().allowed_options().contains(&"-g"));
+    assert!(!backend("PERF").unwrap().allowed_options().contains(&"-o/etc/passwd"));
+}
+
+#[test]
+fn trace_names() {
+    assert!(is_valid_symbol("_ZN4main4mainE"));
+    assert!(is_valid_symbol("memcpy@GLIBC_2.14"));
+    assert!(!is_valid_symbol("main\") { system(\"id\") } probe x(\""));
+    assert!(!is_valid_symbol("main{"));
+    assert!(is_valid_process("/usr/bin/python3.8"));
+    assert!(!is_valid_process("/bin/x\"); system(\"id"));
+    assert!(!is_valid_process("-x"));
+}
+
+#[test]
+fn cgroup_paths() {
+    assert!(is_valid_cgroup("/system.slice/nginx.service"));
+    assert!(is_valid_cgroup("user.slice/user-1000.slice"));
+    assert!(!is_valid_cgroup("x\"; rm -rf / #"));
+    assert!(!is_valid_cgroup("system.slice/../.."));
+    assert!(!is_valid_cgroup("-x"));
+    assert!(!is_valid_cgroup("/"));
+}
@@ -0,0 +1,156 @@
+use crate::config::global_config;
+use crate::db::model::trace::{Capture, KernelProbe, Trace, TraceMode};
+
+use super::{quote, RunOptions, TracerBackend};
+
+// probes are put into a group named after the pid of the script, so that concurrent runs do not
+// collide and the cleanup only removes what this run added
+const PROLOGUE: &str = r#"#!/bin/sh
+GROUP="lambda_$$"
+KGROUP="${GROUP}_k"
+DATA="/tmp/$GROUP.data"
+EVENTS=""
+FUNCS=""
+# event names may hold wildcards, which must reach perf untouched
+set -f
+trap 'exit 1' INT TERM
+trap '$PERF probe -q -d "$GROUP:*"; $PERF probe -q -d "$KGROUP:*" 2>/dev/null; rm -f "$DATA"' EXIT
+"#;
+
+// turns the `perf script` output into the records described in `crate::event`
+const FORMAT: &str = r#"awk -v funcs="$FUNCS" -v group="$GROUP" '
+BEGIN {
+    n = split(funcs, f, " ");
+    for (i = 1; i <= n; i++) { k = index(f[i], "="); name[substr(f[i], 1, k - 1)] = substr(f[i], k + 1) }
+}
+/^[ \t]/ { print; next }
+NF == 0 { next }
+{
+    split($1, id, "/"); ts = $2; sub(/:$/, "", ts); ev = $3; sub(/:$/, "", ev);
+    if (index(ev, group ":") == 1) {
+        sub(/^.*:/, "", ev);
+        kind = ev ~ /^r/ ? "return" : "call";
+        symbol = name[substr(ev, 2)];
+    } else {
+        kind = "kernel";
+        symbol = ev;
+    }
+    vals = "";
+    for (i = 4; i <= NF; i++) if ($i ~ /=/) vals = vals (vals == "" ? "" : " ") $i;
+    printf "E\t%s\t%.0f\t%d\t%d\t%s\t%s\n", kind, ts * 1000000000, id[1], id[2], symbol, vals
+}'
+"#;
+
+// x86_64 System V argument registers
+const REGISTERS: [&str; 6] = ["di", "si", "dx", "cx", "r8", "r9"];
+
+fn fetch(c: &Capture) -> Option<String> {
+    match c {
+        Capture::Arg(n) => REGISTERS.get(*n).map(|r| format!("arg{}=%{}:s64", n, r)),
+        Capture::Return => Some("retval=$retval:s64".to_string()),
+    }
+}
+
+// a cgroup given after an event only applies to the events before it
+fn cgroup_filter(run: &RunOptions) -> &'static str {
+    if run.cgroup.is_some() { " -G $CGROUP" } else { "" }
+}
+
+pub struct Perf;
+
+impl Perf {
+    fn add(trace: &Trace, event: String, spec: String, fetches: Vec<String>, run: &RunOptions) -> String {
+        let definition = format!(":{}={} {}", event, spec, fetches.join(" "));
+        format!("$PERF probe -q -x {} --add \"$GROUP\"{} && EVENTS=\"$EVENTS -e $GROUP:{}{}\"\n",
+                quote(trace.process.as_str()), quote(definition.trim_end()), event, cgroup_filter(run))
+    }
+}
+
+impl TracerBackend for Perf {
+    fn name(&self) -> &'static str {
+        "PERF"
+    }
+
+    fn extension(&self) -> &'static str {
+        "sh"
+    }
+
+    fn binary_path(&self) -> &str {
+        "/bin/sh"
+    }
+
+    fn tool_path(&self) -> &str {
+        global_config().perf_path.as_str()
+    }
+
+    fn allowed_options(&self) -> &'static [&'static str] {
+        &["--call-graph=fp", "--call-graph=dwarf", "--call-graph=lbr"]
+    }
+
+    fn check(&self, trace: &Trace, run: &RunOptions) -> Result<(), String> {
+        if run.mode != TraceMode::Stack {
+            Err("PERF only supports stack mode".to_string())
+        } else if run.pid.is_some() && run.cgroup.is_some() {
+            Err("PERF cannot filter on both pid and cgroup".to_string())
+        } else if !trace.kernel_probes.is_empty() && run.pid.is_none() && run.cgroup.is_none() && run.launch.is_none() {
+            // see `kernel_probe`, the events would be recorded for the whole system
+            Err("PERF needs a pid, a cgroup or a launched process to filter kernel probes".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn prologue(&self, _trace: &Trace, run: &RunOptions) -> String {
+        // checked to hold no blanks, the cgroup survives the word splitting of $EVENTS
+        let cgroup = run.cgroup.as_ref()
+            .map(|x| format!("CGROUP={}\n", quote(x.trim_start_matches('/'))))
+            .unwrap_or_default();
+        format!("PERF={}\n{}{}", quote(global_config().perf_path.as_str()), cgroup, PROLOGUE)
+    }
+
+    fn probe(&self, trace: &Trace, index: usize, function: &str, run: &RunOptions) -> String {
+        let captures = trace.captures_of(index);
+        let args = captures.iter()
+            .filter(|c| **c != Capture::Return)
+            .filter_map(fetch)
+            .collect();
+        let mut probe = format!("FUNCS=\"$FUNCS {}=\"{}\n", index, quote(function));
+        probe.push_str(Self::add(trace, format!("f{}", index), function.to_string(), args, run).as_str());
+        if captures.contains(&Capture::Return) {
+            let ret = fetch(&Capture::Return).into_iter().collect();
+            probe.push_str(Self::add(trace, format!("r{}", index), format!("{}%return", function), ret, run).as_str());
+        }
+        probe
+    }
+
+    fn kernel_probe(&self, _trace: &Trace, index: usize, probe: &KernelProbe, run: &RunOptions) -> Option<String> {
+        // perf cannot filter kernel events on the process name, only pid, cgroup and launch apply
+        let cgroup = cgroup_filter(run);
+        Some(match probe {
+            KernelProbe::Tracepoint(category, name) =>
+                format!("EVENTS=\"$EVENTS -e {}:{}{}\"\n", category, name, cgroup),
+            KernelProbe::Syscall(name) =>
+                format!("EVENTS=\"$EVENTS -e syscalls:sys_enter_{}{}\"\n", name, cgroup),
+            KernelProbe::Kprobe(function) =>
+                format!("$PERF probe -q --add \"$KGROUP\":k{0}={1} && EVENTS=\"$EVENTS -e $KGROUP:k{0}{2}\"\n",
+                        index, function, cgroup),
+        })
+    }
+
+    fn termination(&self, run: &RunOptions) -> String {
+        // a launched process is followed by perf itself, otherwise `sleep` only bounds the duration
+        let (target, command) = match (&run.launch, run.pid) {
+            (Some(_), _) => (String::new(), "/bin/sh \"$0.launch\"".to_string()),
+            (None, Some(pid)) => (format!("-p {}", pid), format!("sleep {}", run.duration)),
+            (None, None) => ("-a".to_string(), format!("sleep {}", run.duration)),
+        };
+        format!("$PERF record -q -g {} -o \"$DATA\" $EVENTS \"$@\" -- {} >&2 || exit 1\n\
+                 $PERF script -i \"$DATA\" -F pid,tid,time,event,trace,ip,sym | {}",
+                target, command, FORMAT)
+    }
+
+    fn check_arguments(&self, script: &str, _run: &RunOptions) -> Option<Vec<String>> {
+        // only the shell syntax is checked, validating the probes would mean adding them to the host
+        Some(vec!["-n".to_string(), script.to_string()])
+    }
+}
@@ -0,0 +1,135 @@
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, ExitStatus, Output};
+use std::sync::mpsc::Receiver;
+
+use parking_lot::Mutex;
+
+/// A running tracer, either a child of the endpoint or one started by the privileged helper.
+/// Running tracers are shared between threads, the receivers are wrapped in a `Mutex` for that
+/// alone and only used through `get_mut`.
+pub struct TracerProcess {
+    pub stdout: Option<Box<dyn Read + Send + Sync>>,
+    pub stderr: Option<Box<dyn Read + Send + Sync>>,
+    handle: Handle,
+}
+
+enum Handle {
+    Local(Child),
+    Helper {
+        socket: UnixStream,
+        exit: Mutex<Receiver<std::io::Result<ExitStatus>>>,
+        status: Option<ExitStatus>,
+    },
+}
+
+impl TracerProcess {
+    pub fn local(mut child: Child) -> Self {
+        TracerProcess {
+            stdout: child.stdout.take().map(|x| box x as Box<dyn Read + Send + Sync>),
+            stderr: child.stderr.take().map(|x| box x as Box<dyn Read + Send + Sync>),
+            handle: Handle::Local(child),
+        }
+    }
+
+    pub(super) fn helper(stdout: Box<dyn Read + Send + Sync>, stderr: Box<dyn Read + Send + Sync>, socket: UnixStream,
+                         exit: Receiver<std::io::Result<ExitStatus>>) -> Self {
+        TracerProcess {
+            stdout: Some(stdout),
+            stderr: Some(stderr),
+            handle: Handle::Helper { socket, exit: Mutex::new(exit), status: None },
+        }
+    }
+
+    /// Ask the tracer to stop. A local tracer may run under sudo, which only relays catchable
+    /// signals, so it gets SIGTERM rather than SIGKILL.
+    pub fn kill(&mut self) -> std::io::Result<()> {
+        match &mut self.handle {
+            Handle::Local(child) => if unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) } == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            },
+            Handle::Helper { socket, .. } => {
+                socket.write_all(b"{\"op\":\"kill\"}\n")?;
+                socket.flush()
+            }
+        }
+    }
+
+    pub fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        match &mut self.handle {
+            Handle::Local(child) => child.wait(),
+            Handle::Helper { exit, status, .. } => {
+                if let Some(s) = status {
+                    return Ok(*s);
+                }
+                let s = exit.get_mut().recv().unwrap_or_else(|_| Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof, "lost the connection to the helper")))?;
+                *status = Some(s);
+                Ok(s)
+            }
+        }
+    }
+
+    pub fn wait_with_output(mut self) -> std::io::Result<Output> {
+        let errors = self.stderr.take().map(|mut x| std::thread::spawn(move || {
+            let mut b = Vec::new();
+            x.read_to_end(&mut b).map(|_| b)
+        }));
+        let mut stdout = Vec::new();
+        if let Some(x) = self.stdout.as_mut() {
+            x.read_to_end(&mut stdout)?;
+        }
+        let stderr = match errors.map(|x| x.join()) {
+            Some(Ok(res)) => res?,
+            Some(Err(_)) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "failed to get stderr")),
+            None => Vec::new()
+        };
+        let status = self.wait()?;
+        Ok(Output { status, stdout, stderr })
+    }
+}
+
+/// Reads the data sent through a channel, ending once the sender is gone.
+pub(super) struct ChannelReader {
+    receiver: Mutex<Receiver<Vec<u8>>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl ChannelReader {
+    pub(super) fn new(receiver: Receiver<Vec<u8>>) -> Self {
+        ChannelReader { receiver: Mutex::new(receiver), buffer: Vec::new(), position: 0 }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.receiver.get_mut().recv() {
+                Ok(data) => {
+                    self.buffer = data;
+                    self.position = 0;
+                }
+                Err(_) => return Ok(0)
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[test]
+fn read_channel() {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    sender.send(b"E\tcall".to_vec()).unwrap();
+    sender.send(Vec::new()).unwrap();
+    sender.send(b"\t1\n".to_vec()).unwrap();
+    drop(sender);
+    let mut res = String::new();
+    ChannelReader::new(receiver).read_to_string(&mut res).unwrap();
+    assert_eq!(res, "E\tcall\t1\n");
+}
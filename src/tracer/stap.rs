@@ -0,0 +1,167 @@
+use crate::config::global_config;
+use crate::db::model::trace::{Capture, KernelProbe, Trace, TraceMode};
+
+use super::{capture_format, launch_command, RunOptions, TracerBackend};
+
+// every probe prints a record as described in `crate::event`
+macro_rules! template {
+    ("call") => {
+r#"
+probe process("{0}").function("{1}").call {{{4}
+    printf("E\tcall\t%d\t%d\t%d\t%s\t{2}\n", gettimeofday_ns(), pid(), tid(), "{1}"{3});
+    print_usyms(ucallers(-1));
+}}
+"#
+    };
+    ("return") => {
+r#"
+probe process("{0}").function("{1}").return {{{4}
+    printf("E\treturn\t%d\t%d\t%d\t%s\t{2}\n", gettimeofday_ns(), pid(), tid(), "{1}"{3});
+}}
+"#
+    };
+    ("kernel") => {
+r#"
+probe {0} {{{1}
+    printf("E\tkernel\t%d\t%d\t%d\t%s\t\n", gettimeofday_ns(), pid(), tid(), pn());
+}}
+"#
+    };
+    ("latency") => {
+r#"
+global entry_{2}, latency_{2}
+
+probe process("{0}").function("{1}").call {{{3}
+    entry_{2}[tid()] = gettimeofday_ns();
+}}
+
+probe process("{0}").function("{1}").return {{
+    if (tid() in entry_{2}) {{
+        latency_{2} <<< gettimeofday_ns() - entry_{2}[tid()];
+        delete entry_{2}[tid()];
+    }}
+}}
+
+probe end {{
+    if (@count(latency_{2})) {{
+        printf("E\tlatency\t%d\t0\t0\t%s\t\n", gettimeofday_ns(), "{1}");
+        print(@hist_log(latency_{2}));
+    }}
+}}
+"#
+    };
+}
+
+fn kernel_point(probe: &KernelProbe) -> String {
+    match probe {
+        KernelProbe::Tracepoint(category, name) => format!("kernel.trace(\"{}:{}\")", category, name),
+        KernelProbe::Kprobe(function) => format!("kernel.function(\"{}\")", function),
+        KernelProbe::Syscall(name) => format!("syscall.{}", name),
+    }
+}
+
+fn expr(c: &Capture) -> String {
+    match c {
+        Capture::Arg(n) => format!("long_arg({})", n + 1),
+        Capture::Return => "returnval()".to_string(),
+    }
+}
+
+fn guard(run: &RunOptions) -> &'static str {
+    if run.pid.is_some() || run.launch.is_some() {
+        "\n    if (pid() != target()) next;"
+    } else {
+        ""
+    }
+}
+
+pub struct SystemTap;
+
+impl TracerBackend for SystemTap {
+    fn name(&self) -> &'static str {
+        "STAP"
+    }
+
+    fn extension(&self) -> &'static str {
+        "stap"
+    }
+
+    fn binary_path(&self) -> &str {
+        global_config().stap_path.as_str()
+    }
+
+    fn allowed_options(&self) -> &'static [&'static str] {
+        // not `-g` nor `--unsafe`, guru mode runs embedded C in the kernel
+        &["-v", "-vv", "-w", "-t"]
+    }
+
+    fn arguments(&self, script: &str, options: &[String], run: &RunOptions) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(pid) = run.pid {
+            args.push("-x".to_string());
+            args.push(pid.to_string());
+        }
+        if run.launch.is_some() {
+            args.push("-c".to_string());
+            args.push(launch_command(script));
+        }
+        args.push(script.to_string());
+        args.extend(options.iter().cloned());
+        args
+    }
+
+    fn check(&self, _trace: &Trace, run: &RunOptions) -> Result<(), String> {
+        if run.cgroup.is_some() {
+            Err("cgroup filter is not supported by STAP".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn probe(&self, trace: &Trace, index: usize, function: &str, run: &RunOptions) -> String {
+        if run.mode == TraceMode::Latency {
+            return format!(template!("latency"), trace.process, function, index, guard(run));
+        }
+        let captures = trace.captures_of(index);
+        let args = captures.iter().filter(|c| **c != Capture::Return).collect::<Vec<_>>();
+        let (fmt, vals) = capture_format(&args, expr);
+        let mut probe = format!(template!("call"), trace.process, function, fmt, vals, guard(run));
+        if captures.contains(&Capture::Return) {
+            let (fmt, vals) = capture_format(&[&Capture::Return], expr);
+            probe.push_str(format!(template!("return"), trace.process, function, fmt, vals, guard(run)).as_str());
+        }
+        probe
+    }
+
+    fn kernel_probe(&self, trace: &Trace, _index: usize, probe: &KernelProbe, run: &RunOptions) -> Option<String> {
+        // kernel probes fire for every process, fall back to the name of the traced one
+        let guard = if run.pid.is_some() || run.launch.is_some() {
+            guard(run).to_string()
+        } else {
+            format!("\n    if (execname() != \"{}\") next;", trace.comm())
+        };
+        Some(format!(template!("kernel"), kernel_point(probe), guard))
+    }
+
+    fn template_probe(&self, process: &str, function: &str, body: &str, run: &RunOptions) -> Option<String> {
+        Some(format!("\nprobe process(\"{}\").function(\"{}\").call {{{}\n{}\n}}\n", process, function, guard(run), body))
+    }
+
+    fn termination(&self, run: &RunOptions) -> String {
+        if run.launch.is_some() {
+            return String::new();
+        }
+        format!("probe timer.s({}) {{exit(); }}\n", run.duration)
+    }
+
+    fn check_arguments(&self, script: &str, run: &RunOptions) -> Option<Vec<String>> {
+        // pass 4 builds the kernel module, which resolves every probe point without loading it
+        let mut args = vec!["-p4".to_string()];
+        if let Some(pid) = run.pid {
+            args.push("-x".to_string());
+            args.push(pid.to_string());
+        }
+        args.push(script.to_string());
+        Some(args)
+    }
+}